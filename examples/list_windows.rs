@@ -0,0 +1,22 @@
+fn main() {
+    match kdotool::list_windows() {
+        Ok(windows) => {
+            for w in windows {
+                println!(
+                    "{}\t{}\t{}\t{},{} {}x{}",
+                    w.id,
+                    w.resource_class,
+                    w.caption,
+                    w.geometry.x,
+                    w.geometry.y,
+                    w.geometry.width,
+                    w.geometry.height
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,2347 @@
+//! The script engine behind both the `kdotool` CLI and the library API.
+//!
+//! A command chain (e.g. `search --class firefox windowactivate`) is compiled
+//! into one KWin script, which is loaded into KWin over D-Bus and run. The
+//! script reports back by calling methods on our own D-Bus connection.
+
+use std::io::Write;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, anyhow};
+use dbus::{
+    blocking::{Connection, SyncConnection},
+    channel::MatchingReceiver,
+    message::MatchRule,
+};
+use serde::Serialize;
+
+use crate::Error;
+use crate::expr::compile_where;
+use crate::parser::*;
+use crate::templates::*;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Globals {
+    /// Unique D-Bus name the generated script reports back to.
+    pub dbus_addr: String,
+    pub cmdline: String,
+    pub debug: bool,
+    /// Prefix of the script's lines in the KWin log.
+    pub marker: String,
+    pub script_name: String,
+    pub shortcut: String,
+    /// How long to wait for the script to finish, in milliseconds.
+    pub timeout_ms: u64,
+    /// Output query results as JSON objects instead of text.
+    pub json: bool,
+    /// Leave scripts that keep running, e.g. `behave`, loaded in KWin
+    /// instead of staying attached to them.
+    pub detach: bool,
+    /// Format string applied to each window in the final window stack, e.g.
+    /// `{id}\t{title}`. Empty for the default output.
+    pub format: String,
+    /// How desktops are output: "number", "name" or "id". Empty means
+    /// "number".
+    pub desktop_format: String,
+}
+
+impl Globals {
+    /// Creates globals with a fresh marker, which is also used as the script
+    /// name.
+    pub fn new(cmdline: &str) -> anyhow::Result<Self> {
+        let unique_suffix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("failed to read system time")?
+            .as_millis();
+        let marker = format!("kdotool-{}-{unique_suffix}", std::process::id());
+        Ok(Globals {
+            cmdline: cmdline.to_string(),
+            script_name: marker.clone(),
+            marker,
+            timeout_ms: 5000,
+            ..Default::default()
+        })
+    }
+}
+
+/// A message sent back by a running script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Result(String),
+    Error(String),
+    Debug(String),
+}
+
+struct StepResult {
+    script: String,
+    /// Closes what `script` leaves open, after the rest of the chain. Steps
+    /// that wait for something (e.g. `--sync`) continue the chain in a
+    /// callback.
+    closing: String,
+    is_query: bool,
+    next_arg: Option<String>,
+    /// The step uses `activity_list`, the activities with their names. They
+    /// are loaded over D-Bus before the chain runs.
+    needs_activities: bool,
+}
+
+pub(crate) fn add_context<T>(render_context: &mut handlebars::Context, key: &str, value: T)
+where
+    serde_json::Value: From<T>,
+{
+    render_context
+        .data_mut()
+        .as_object_mut()
+        .unwrap()
+        .insert(key.into(), serde_json::Value::from(value));
+}
+
+/// Generates the KWin script for the command chain starting with `next_arg`,
+/// reading the remaining arguments from `parser`.
+pub fn generate_script(
+    globals: &Globals,
+    parser: Parser,
+    next_arg: &str,
+) -> anyhow::Result<String> {
+    if next_arg == "kwinscript" {
+        return generate_kwinscript(globals, parser);
+    }
+
+    let mut full_script = String::new();
+    let reg = new_registry();
+    let render_context = handlebars::Context::wraps(globals)?;
+
+    full_script.push_str(&reg.render_template_with_context(SCRIPT_HEADER, &render_context)?);
+
+    let chain = generate_chain(globals, parser, &reg, &render_context, next_arg)?;
+    if chain.needs_activities {
+        full_script.push_str(STEP_ACTIVITIES_LOAD);
+    }
+    full_script.push_str(&chain.script);
+    if chain.persistent {
+        full_script.push_str(&reg.render_template_with_context(STEP_ATTACHED, &render_context)?);
+    } else {
+        if chain.last_step_is_query {
+            full_script.push_str(&render_last_output(globals, &reg, &render_context)?);
+        }
+        full_script.push_str(&reg.render_template_with_context(STEP_DONE, &render_context)?);
+    }
+    full_script.push_str(&chain.closing);
+    if chain.needs_activities {
+        full_script.push_str(STEP_ACTIVITIES_CLOSING);
+    }
+
+    full_script.push_str(&reg.render_template_with_context(SCRIPT_FOOTER, &render_context)?);
+
+    Ok(full_script)
+}
+
+struct Chain {
+    script: String,
+    /// Closes the callbacks of steps that wait, see `StepResult::closing`.
+    closing: String,
+    last_step_is_query: bool,
+    /// The chain ends with a command that keeps the script running, e.g.
+    /// `behave`.
+    persistent: bool,
+    /// Some step needs the activity names, see `StepResult::needs_activities`.
+    needs_activities: bool,
+}
+
+/// Generates the steps of a command chain, starting with `command` and
+/// reading the remaining commands from `parser`.
+fn generate_chain(
+    globals: &Globals,
+    mut parser: Parser,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+    command: &str,
+) -> anyhow::Result<Chain> {
+    use lexopt::prelude::*;
+
+    let mut chain = Chain {
+        script: String::new(),
+        closing: String::new(),
+        last_step_is_query: false,
+        persistent: false,
+        needs_activities: false,
+    };
+    let mut command: String = command.into();
+    // Open control blocks, e.g. "foreach", innermost last.
+    let mut blocks: Vec<&'static str> = Vec::new();
+
+    loop {
+        parser = reset_parser(parser)?;
+
+        if command == "behave" || command == "watch" {
+            if let Some(block) = blocks.last() {
+                return Err(anyhow!("'{command}' can't be used inside '{block}'"));
+            }
+            // These keep running, and take the rest of the command line.
+            let (script, needs_activities) = if command == "behave" {
+                generate_behave(globals, parser, reg, render_context)
+            } else {
+                generate_watch(parser, reg, render_context).map(|script| (script, false))
+            }
+            .with_context(|| format!("in command '{command}'"))?;
+            chain.script.push_str(&script);
+            chain.needs_activities |= needs_activities;
+            chain.last_step_is_query = false;
+            chain.persistent = true;
+            return Ok(chain);
+        }
+
+        let step_result = if CONTROL_COMMANDS.contains(&command.as_str()) {
+            step_control(&command, &mut blocks, reg, render_context)
+        } else {
+            generate_step(&command, &mut parser, reg, render_context, globals)
+        }
+        .with_context(|| format!("in command '{command}'"))?;
+
+        if let Some(block) = blocks.last()
+            && !step_result.closing.is_empty()
+        {
+            // The rest of the chain would continue in a callback, after the
+            // block has already ended.
+            return Err(anyhow!(
+                "in command '{command}': --sync can't be used inside '{block}'"
+            ));
+        }
+
+        chain.script.push_str(&step_result.script);
+        chain.closing.insert_str(0, &step_result.closing);
+        chain.needs_activities |= step_result.needs_activities;
+        chain.last_step_is_query = step_result.is_query;
+
+        if let Some(next_arg) = step_result.next_arg {
+            command = next_arg;
+        } else {
+            match parser.next()? {
+                Some(Value(val)) => {
+                    command = val.string()?;
+                }
+
+                None => {
+                    if let Some(block) = blocks.last() {
+                        return Err(anyhow!("missing 'end' for '{block}'"));
+                    }
+                    return Ok(chain);
+                }
+
+                Some(arg) => {
+                    return Err(arg.unexpected().into());
+                }
+            }
+        }
+    }
+}
+
+const CONTROL_COMMANDS: &[&str] = &["if-empty", "else", "end", "foreach", "exit-if-empty"];
+
+/// Generates a control step, which opens or closes a block of the chain.
+/// `blocks` are the blocks open before it.
+fn step_control(
+    command: &str,
+    blocks: &mut Vec<&'static str>,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+) -> anyhow::Result<StepResult> {
+    let template = match command {
+        "if-empty" => {
+            blocks.push("if-empty");
+            STEP_IF_EMPTY
+        }
+        "else" => {
+            if blocks.last() != Some(&"if-empty") {
+                return Err(anyhow!("'else' without 'if-empty'"));
+            }
+            *blocks.last_mut().unwrap() = "else";
+            STEP_ELSE
+        }
+        "end" => match blocks.pop() {
+            Some("foreach") => STEP_END_FOREACH,
+            Some(_) => STEP_END_IF,
+            None => return Err(anyhow!("'end' without 'if-empty' or 'foreach'")),
+        },
+        "foreach" => {
+            blocks.push("foreach");
+            STEP_FOREACH
+        }
+        "exit-if-empty" => STEP_EXIT_IF_EMPTY,
+        _ => unreachable!(),
+    };
+    Ok(StepResult {
+        script: reg.render_template_with_context(template, render_context)?,
+        closing: String::new(),
+        is_query: false,
+        next_arg: None,
+        needs_activities: false,
+    })
+}
+
+/// Renders the output of the final window stack, for chains ending with a
+/// window query.
+fn render_last_output(
+    globals: &Globals,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+) -> anyhow::Result<String> {
+    let mut render_context = render_context.clone();
+    if !globals.format.is_empty() {
+        add_context(
+            &mut render_context,
+            "format_expr",
+            format_script(&globals.format)?,
+        );
+    }
+    Ok(reg.render_template_with_context(STEP_LAST_OUTPUT, &render_context)?)
+}
+
+/// Adds `event_NAME` for each event in the comma separated list `events`, and
+/// `per_window` if any of them needs to be connected to every window.
+fn add_events_context<'a>(
+    render_context: &mut handlebars::Context,
+    events: &'a str,
+    allowed: &[&str],
+) -> anyhow::Result<Vec<&'a str>> {
+    let events: Vec<&str> = events.split(',').collect();
+    for event in &events {
+        if !allowed.contains(event) {
+            return Err(anyhow!(
+                "unknown event '{event}', expected one of: {}",
+                allowed.join(", ")
+            ));
+        }
+        add_context(render_context, &format!("event_{event}"), true);
+    }
+    let per_window = events.iter().any(|event| WINDOW_EVENTS.contains(event));
+    add_context(render_context, "per_window", per_window);
+    Ok(events)
+}
+
+/// Generates `watch [--events EVENT[,EVENT...]]`, which reports window events
+/// as JSON objects.
+fn generate_watch(
+    mut parser: Parser,
+    reg: &handlebars::Handlebars,
+    base_context: &handlebars::Context,
+) -> anyhow::Result<String> {
+    use lexopt::prelude::*;
+
+    let mut opt_events = WATCH_EVENTS.join(",");
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("events") => {
+                opt_events = parser.value()?.string()?;
+            }
+            _ => {
+                return Err(arg.unexpected().into());
+            }
+        }
+    }
+
+    let mut render_context = base_context.clone();
+    add_context(&mut render_context, "step_name", "watch");
+    add_events_context(&mut render_context, &opt_events, WATCH_EVENTS)?;
+    Ok(reg.render_template_with_context(STEP_WATCH, &render_context)?)
+}
+
+/// Generates `behave [WINDOW] EVENT[,EVENT...] COMMAND...`, which runs the
+/// rest of the command chain for every matching window event. Also returns
+/// whether the handler needs the activity names.
+fn generate_behave(
+    globals: &Globals,
+    mut parser: Parser,
+    reg: &handlebars::Handlebars,
+    base_context: &handlebars::Context,
+) -> anyhow::Result<(String, bool)> {
+    use lexopt::prelude::*;
+
+    let mut arg_window_id: Option<String> = None;
+    let arg_events = loop {
+        match parser.next()? {
+            Some(Value(val)) => {
+                let s = val.string()?;
+                match to_window_id(&s) {
+                    Some(id) if arg_window_id.is_none() => arg_window_id = Some(id),
+                    _ => break s,
+                }
+            }
+            Some(arg) => return Err(arg.unexpected().into()),
+            None => return Err(anyhow!("missing argument 'event'")),
+        }
+    };
+
+    let mut render_context = base_context.clone();
+    add_context(&mut render_context, "step_name", "behave");
+    let events = add_events_context(&mut render_context, &arg_events, BEHAVE_EVENTS)?;
+
+    match arg_window_id {
+        Some(_) if events.contains(&"added") => {
+            return Err(anyhow!("the 'added' event can't be used with a window"));
+        }
+        Some(id) if id == "%@" => {
+            add_context(&mut render_context, "all_windows", true);
+        }
+        Some(id) if id.starts_with('%') => {
+            add_context(&mut render_context, "has_selection", true);
+            add_context(&mut render_context, "item_index", parse_stack_index(&id)?);
+        }
+        Some(id) => {
+            add_context(&mut render_context, "window_id", id);
+        }
+        None => {}
+    }
+
+    let command = match parser.next()? {
+        Some(Value(val)) => val.string()?,
+        Some(arg) => return Err(arg.unexpected().into()),
+        None => return Err(anyhow!("missing command to run on events")),
+    };
+    let mut handler_context = base_context.clone();
+    add_context(&mut handler_context, "in_handler", true);
+    let chain = generate_chain(globals, parser, reg, &handler_context, &command)?;
+    if chain.persistent {
+        return Err(anyhow!("behave can't be nested"));
+    }
+    let mut handler = chain.script;
+    if chain.last_step_is_query {
+        handler.push_str(&render_last_output(globals, reg, base_context)?);
+    }
+    handler.push_str(&chain.closing);
+    add_context(&mut render_context, "handler", handler);
+
+    Ok((
+        reg.render_template_with_context(STEP_BEHAVE, &render_context)?,
+        chain.needs_activities,
+    ))
+}
+
+fn generate_step(
+    command: &str,
+    parser: &mut Parser,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+    globals: &Globals,
+) -> anyhow::Result<StepResult> {
+    use lexopt::prelude::*;
+
+    let mut step_script;
+    let mut closing = String::new();
+    let mut is_query = false;
+    let mut next_arg = None;
+    let mut render_context = render_context.clone();
+    add_context(&mut render_context, "step_name", command);
+
+    match command {
+        "search" | "stackfilter" => {
+            return step_search(parser, reg, &render_context, command == "stackfilter");
+        }
+
+        "getactivewindow" => {
+            step_script =
+                reg.render_template_with_context(STEP_GETACTIVEWINDOW, &render_context)?;
+            is_query = true;
+        }
+
+        "savewindowstack" | "loadwindowstack" => {
+            let mut arg_name = None;
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Value(val) if arg_name.is_none() => {
+                        arg_name = Some(val.string()?);
+                    }
+                    Value(val) => {
+                        next_arg = Some(val.string()?);
+                        break;
+                    }
+                    _ => {
+                        return Err(arg.unexpected().into());
+                    }
+                }
+            }
+            let mut render_context = render_context.clone();
+            add_context(
+                &mut render_context,
+                "name",
+                arg_name.ok_or(anyhow!("missing argument 'name'"))?.as_str(),
+            );
+            step_script = reg.render_template_with_context(
+                if command == "savewindowstack" {
+                    STEP_SAVEWINDOWSTACK
+                } else {
+                    STEP_LOADWINDOWSTACK
+                },
+                &render_context,
+            )?;
+            is_query = command == "loadwindowstack";
+        }
+
+        "stackunion" | "stackintersect" | "stackdiff" => {
+            let name = match parser.next()? {
+                Some(Value(val)) => val.string()?,
+                Some(arg) => return Err(arg.unexpected().into()),
+                None => return Err(anyhow!("missing argument 'name'")),
+            };
+            let mut render_context = render_context.clone();
+            add_context(&mut render_context, "name", name);
+            add_context(&mut render_context, "op", *STACK_OPS.get(command).unwrap());
+            step_script = reg.render_template_with_context(STEP_STACK_OP, &render_context)?;
+            is_query = true;
+        }
+
+        "stackslice" => {
+            let mut arg_start: Option<i32> = None;
+            let mut arg_end: Option<i32> = None;
+            while let Some(arg) = next_maybe_num(parser)? {
+                match arg {
+                    Value(val) if arg_start.is_none() => {
+                        arg_start = Some(val.parse()?);
+                    }
+                    Value(val) if arg_end.is_none() => {
+                        let s = val.string()?;
+                        if let Ok(n) = s.parse() {
+                            arg_end = Some(n);
+                        } else {
+                            next_arg = Some(s);
+                            break;
+                        }
+                    }
+                    Value(val) => {
+                        next_arg = Some(val.string()?);
+                        break;
+                    }
+                    _ => {
+                        return Err(arg.unexpected().into());
+                    }
+                }
+            }
+            let start = arg_start.ok_or(anyhow!("missing argument 'start'"))?;
+            let end = arg_end.unwrap_or(-1);
+            if start == 0 || end == 0 {
+                return Err(anyhow!("window stack positions start at 1"));
+            }
+            let mut render_context = render_context.clone();
+            add_context(&mut render_context, "start", start);
+            add_context(&mut render_context, "end", end);
+            step_script = reg.render_template_with_context(STEP_STACKSLICE, &render_context)?;
+            is_query = true;
+        }
+
+        "stackcount" => {
+            step_script = reg.render_template_with_context(STEP_STACKCOUNT, &render_context)?;
+        }
+
+        _ => {
+            if WINDOW_ACTIONS.contains_key(command) {
+                let mut arg_window_id: Option<String> = None;
+                let mut opt_sync = false;
+
+                let action_script;
+                match command {
+                    "windowstate" => {
+                        let mut opt_windowstate = String::new();
+
+                        while let Some(arg) = parser.next()? {
+                            match arg {
+                                Long(option)
+                                    if option == "add"
+                                        || option == "remove"
+                                        || option == "toggle" =>
+                                {
+                                    let option: String = option.into();
+                                    let key = parser.value()?.string()?;
+                                    opt_windowstate.push_str(&windowstate_script(&option, &key)?);
+                                }
+                                Value(val) if arg_window_id.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        next_arg = Some(s);
+                                        break;
+                                    }
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "windowstate", opt_windowstate);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "windowmove" | "windowsize" => {
+                        let mut opt_relative = false;
+                        let mut arg_x: Option<String> = None;
+                        let mut arg_y: Option<String> = None;
+
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("relative") if command == "windowmove" => {
+                                    opt_relative = true;
+                                }
+                                Long("sync") => {
+                                    opt_sync = true;
+                                }
+                                Value(val) if arg_window_id.is_none() && arg_x.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        arg_x = Some(s);
+                                    }
+                                }
+                                Value(val) if arg_x.is_none() => {
+                                    arg_x = Some(val.string()?);
+                                }
+                                Value(val) if arg_y.is_none() => {
+                                    arg_y = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+
+                        let mut x = String::new();
+                        let mut y = String::new();
+                        let mut x_percent = String::new();
+                        let mut y_percent = String::new();
+
+                        if let Some(arg) = arg_x {
+                            if arg != "x" {
+                                if arg.ends_with('%') {
+                                    let s = arg.strip_suffix('%').unwrap();
+                                    _ = s.parse::<i32>()?;
+                                    x_percent = s.into();
+                                } else {
+                                    _ = arg.parse::<i32>()?;
+                                    x = arg;
+                                }
+                            }
+                        } else {
+                            return Err(anyhow!("missing argument 'x'"));
+                        }
+
+                        if let Some(arg) = arg_y {
+                            if arg != "y" {
+                                if arg.ends_with('%') {
+                                    let s = arg.strip_suffix('%').unwrap();
+                                    _ = s.parse::<i32>()?;
+                                    y_percent = s.into();
+                                } else {
+                                    _ = arg.parse::<i32>()?;
+                                    y = arg;
+                                }
+                            }
+                        } else {
+                            return Err(anyhow!("missing argument 'y'"));
+                        }
+
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "relative", opt_relative);
+                        add_context(&mut render_context, "sync", opt_sync);
+                        add_context(&mut render_context, "x", x);
+                        add_context(&mut render_context, "y", y);
+                        add_context(&mut render_context, "x_percent", x_percent);
+                        add_context(&mut render_context, "y_percent", y_percent);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "set_desktop_for_window" => {
+                        let mut target = DesktopTarget::default();
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("relative") => {
+                                    target.relative = true;
+                                }
+                                Long("wrap") => {
+                                    target.wrap = true;
+                                }
+                                Long("direction") => {
+                                    target.direction = Some(parser.value()?.string()?);
+                                }
+                                Value(val)
+                                    if arg_window_id.is_none() && target.desktop.is_none() =>
+                                {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else if target.direction.is_some() {
+                                        next_arg = Some(s);
+                                        break;
+                                    } else {
+                                        target.desktop = Some(s);
+                                    }
+                                }
+                                Value(val)
+                                    if target.desktop.is_none() && target.direction.is_none() =>
+                                {
+                                    target.desktop = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let mut render_context = render_context.clone();
+                        target.add_context(&mut render_context, true)?;
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "add_desktop_for_window" | "remove_desktop_for_window" => {
+                        let mut arg_desktop: Option<String> = None;
+                        while let Some(arg) = parser.next()? {
+                            match arg {
+                                Value(val) if arg_window_id.is_none() && arg_desktop.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        arg_desktop = Some(s);
+                                    }
+                                }
+                                Value(val) if arg_desktop.is_none() => {
+                                    arg_desktop = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let desktop = match arg_desktop {
+                            Some(id) if id.eq_ignore_ascii_case("current_desktop") => {
+                                serde_json::Value::from(-1)
+                            }
+                            Some(id) => serde_json::Value::from(id),
+                            None => return Err(anyhow!("missing argument 'desktop'")),
+                        };
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "desktop", desktop);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "set_activities_for_window" => {
+                        let mut arg_activities: Option<String> = None;
+                        while let Some(arg) = parser.next()? {
+                            match arg {
+                                Value(val)
+                                    if arg_window_id.is_none() && arg_activities.is_none() =>
+                                {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        arg_activities = Some(s);
+                                    }
+                                }
+                                Value(val) if arg_activities.is_none() => {
+                                    arg_activities = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let activities: Vec<String> = arg_activities
+                            .ok_or(anyhow!("missing argument 'activity'"))?
+                            .split(',')
+                            .map(String::from)
+                            .collect();
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "activities", activities);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    _ => {
+                        let mut opt_shell = false;
+                        let mut opt_all = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("all") if command == "get_desktop_for_window" => {
+                                    opt_all = true;
+                                }
+                                Long("shell")
+                                    if matches!(
+                                        command,
+                                        "getwindowgeometry"
+                                            | "getwindowname"
+                                            | "getwindowpid"
+                                            | "get_desktop_for_window"
+                                    ) =>
+                                {
+                                    opt_shell = true;
+                                }
+                                Long("sync")
+                                    if matches!(command, "windowactivate" | "windowminimize") =>
+                                {
+                                    opt_sync = true;
+                                }
+                                Value(val) if arg_window_id.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        next_arg = Some(s);
+                                        break;
+                                    }
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "shell", opt_shell);
+                        add_context(&mut render_context, "all", opt_all);
+                        add_context(&mut render_context, "sync", opt_sync);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+                };
+
+                let window_id = arg_window_id.unwrap_or("%1".into());
+                let mut render_context = render_context.clone();
+                add_context(&mut render_context, "action", action_script);
+
+                if window_id == "%@" {
+                    step_script = reg
+                        .render_template_with_context(STEP_ACTION_ON_STACK_ALL, &render_context)?;
+                } else if window_id.starts_with('%') {
+                    let index = parse_stack_index(&window_id)?;
+                    let mut render_context = render_context.clone();
+                    add_context(&mut render_context, "item_index", index);
+                    step_script = reg
+                        .render_template_with_context(STEP_ACTION_ON_STACK_ITEM, &render_context)?;
+                } else {
+                    let mut render_context = render_context.clone();
+                    add_context(&mut render_context, "window_id", window_id);
+                    step_script = reg
+                        .render_template_with_context(STEP_ACTION_ON_WINDOW_ID, &render_context)?;
+                }
+
+                if opt_sync {
+                    (step_script, closing) = wrap_sync(reg, &step_script, globals.timeout_ms)?;
+                }
+            } else if GLOBAL_ACTIONS.contains_key(command.as_ref()) {
+                let action_script;
+                match command {
+                    "set_desktop" => {
+                        let mut target = DesktopTarget::default();
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("relative") => {
+                                    target.relative = true;
+                                }
+                                Long("wrap") => {
+                                    target.wrap = true;
+                                }
+                                Long("direction") => {
+                                    target.direction = Some(parser.value()?.string()?);
+                                }
+                                Value(val)
+                                    if target.desktop.is_none() && target.direction.is_none() =>
+                                {
+                                    target.desktop = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        target.add_context(&mut render_context, false)?;
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "set_num_desktops" => {
+                        let mut arg_n: Option<i32> = None;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Value(val) if arg_n.is_none() => {
+                                    arg_n = Some(val.parse()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let n = arg_n.ok_or(anyhow!("missing argument 'num'"))?;
+                        if n < 1 {
+                            return Err(anyhow!("the number of desktops must be at least 1"));
+                        }
+                        add_context(&mut render_context, "n", n);
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "desktop_create" => {
+                        let mut arg_name: Option<String> = None;
+                        let mut arg_position: Option<i32> = None;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Value(val) if arg_name.is_none() => {
+                                    arg_name = Some(val.string()?);
+                                }
+                                Value(val) if arg_position.is_none() => {
+                                    let s = val.string()?;
+                                    if let Ok(n) = s.parse::<i32>() {
+                                        if n < 1 {
+                                            return Err(anyhow!("invalid position {n}"));
+                                        }
+                                        arg_position = Some(n);
+                                    } else {
+                                        next_arg = Some(s);
+                                        break;
+                                    }
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let name = arg_name.ok_or(anyhow!("missing argument 'name'"))?;
+                        add_context(&mut render_context, "name", name);
+                        add_context(&mut render_context, "position", arg_position.unwrap_or(0));
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "desktop_remove" | "desktop_rename" => {
+                        let mut arg_desktop: Option<String> = None;
+                        let mut arg_name: Option<String> = None;
+                        while let Some(arg) = parser.next()? {
+                            match arg {
+                                Value(val) if arg_desktop.is_none() => {
+                                    arg_desktop = Some(val.string()?);
+                                }
+                                Value(val) if command == "desktop_rename" && arg_name.is_none() => {
+                                    arg_name = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let desktop = arg_desktop.ok_or(anyhow!("missing argument 'desktop'"))?;
+                        add_context(&mut render_context, "desktop", desktop);
+                        if command == "desktop_rename" {
+                            let name = arg_name.ok_or(anyhow!("missing argument 'name'"))?;
+                            add_context(&mut render_context, "name", name);
+                        }
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "set_activity" => {
+                        let mut arg_activity: Option<String> = None;
+                        while let Some(arg) = parser.next()? {
+                            match arg {
+                                Value(val) if arg_activity.is_none() => {
+                                    arg_activity = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let activity =
+                            arg_activity.ok_or(anyhow!("missing argument 'activity'"))?;
+                        add_context(&mut render_context, "activity", activity);
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "getmouselocation" => {
+                        let mut opt_shell = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("shell") => {
+                                    opt_shell = true;
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        add_context(&mut render_context, "shell", opt_shell);
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    _ => {
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+                };
+
+                let mut render_context = render_context.clone();
+                add_context(&mut render_context, "action", action_script);
+                step_script =
+                    reg.render_template_with_context(STEP_GLOBAL_ACTION, &render_context)?;
+            } else {
+                return Err(anyhow!("Unknown command: {command}"));
+            }
+        }
+    }
+
+    Ok(StepResult {
+        script: step_script,
+        closing,
+        is_query,
+        next_arg,
+        needs_activities: ACTIVITY_COMMANDS.contains(&command),
+    })
+}
+
+/// Generates `search`, or with `from_stack` `stackfilter`, which searches the
+/// window stack instead of all windows. The pattern of `stackfilter` is
+/// optional, so a command name ends its arguments.
+fn step_search(
+    parser: &mut Parser,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+    from_stack: bool,
+) -> anyhow::Result<StepResult> {
+    use lexopt::prelude::*;
+
+    let context = render_context.data().as_object().unwrap();
+    let mut opt = SearchOptions {
+        debug: context.get("debug").unwrap().as_bool().unwrap(),
+        from_stack,
+        ..Default::default()
+    };
+    let mut timeout_ms = context.get("timeout_ms").unwrap().as_u64().unwrap();
+    let mut opt_exact = false;
+
+    let mut next_arg = None;
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("sync") => {
+                opt.sync = true;
+            }
+            Long("timeout") => {
+                timeout_ms = parse_timeout(&parser.value()?.string()?)?;
+            }
+            Short('C') | Long("case-sensitive") => {
+                opt.match_case = true;
+            }
+            Short('c') | Long("class") => {
+                opt.match_class = true;
+            }
+            Short('n') | Long("classname") => {
+                opt.match_classname = true;
+            }
+            Short('r') | Long("role") => {
+                opt.match_role = true;
+            }
+            Short('t') | Long("title") | Long("name") => {
+                opt.match_name = true;
+            }
+            Short('p') | Long("pid") => {
+                opt.match_pid = true;
+                opt.pid = parser.value()?.parse()?;
+            }
+            Long("id") => {
+                opt.match_id = true;
+            }
+            Long("desktopfile") => {
+                opt.match_desktopfile = true;
+            }
+            Long("exact") => {
+                opt_exact = true;
+            }
+            Long("activity") => {
+                opt.match_activity = true;
+                opt.activity = parser.value()?.string()?;
+            }
+            Long("type") => {
+                for name in parser.value()?.string()?.split(',') {
+                    opt.window_types
+                        .push(window_type_property(name)?.to_string());
+                }
+            }
+            Short('D') | Long("desktop") => {
+                opt.match_desktop = true;
+                opt.desktop = parser.value()?.string()?;
+            }
+            Long("screen") | Long("output") => {
+                opt.match_screen = true;
+                opt.screen = parser.value()?.string()?;
+            }
+            Long("onlyvisible") => {
+                opt.only_visible = true;
+            }
+            Long("minimized") | Long("not-minimized") => {
+                opt.match_minimized = true;
+                opt.minimized = arg == Long("minimized");
+            }
+            Long("fullscreen") => {
+                opt.fullscreen = true;
+            }
+            Long("above") => {
+                opt.above = true;
+            }
+            Long("normal-only") => {
+                opt.normal_only = true;
+            }
+            Long("where") => {
+                opt.where_expr = compile_where(&parser.value()?.string()?)?;
+            }
+            Long("sort") => {
+                opt.sort = sort_key(&parser.value()?.string()?)?.to_string();
+            }
+            Long("reverse") => {
+                opt.reverse = true;
+            }
+            Short('l') | Long("limit") => {
+                opt.limit = parser.value()?.parse()?;
+            }
+            Short('a') | Long("all") => {
+                opt.match_all = true;
+            }
+            Long("any") => {
+                opt.match_all = false;
+            }
+            Value(val) => {
+                let val = val.string()?;
+                if opt.search_term.is_empty() && !(from_stack && is_command(&val)) {
+                    opt.search_term = val;
+                } else {
+                    next_arg = Some(val);
+                    break;
+                }
+            }
+            _ => {
+                return Err(arg.unexpected().into());
+            }
+        }
+    }
+    if from_stack && opt.sync {
+        return Err(anyhow!("--sync is not supported by stackfilter"));
+    }
+    if opt.reverse && opt.sort.is_empty() {
+        return Err(anyhow!("--reverse requires --sort"));
+    }
+    if !(opt.match_class
+        || opt.match_classname
+        || opt.match_role
+        || opt.match_name
+        || opt.match_id
+        || opt.match_desktopfile)
+    {
+        opt.match_class = true;
+        opt.match_classname = true;
+        opt.match_role = true;
+        opt.match_name = true;
+        opt.match_id = true;
+    }
+    let pattern = if opt_exact {
+        format!("^{}$", regex_escape(&opt.search_term))
+    } else {
+        opt.search_term.clone()
+    };
+    opt.class_pattern.clone_from(&pattern);
+    opt.classname_pattern.clone_from(&pattern);
+    opt.role_pattern.clone_from(&pattern);
+    opt.name_pattern.clone_from(&pattern);
+    opt.id_pattern.clone_from(&pattern);
+    opt.desktopfile_pattern = pattern;
+
+    // Other activities than ids are looked up by name.
+    opt.activity_by_name =
+        opt.match_activity && opt.activity != "current" && !is_uuid(&opt.activity);
+
+    let mut script = render_search(reg, &opt)?;
+    let mut closing = String::new();
+    if opt.sync {
+        (script, closing) = wrap_sync(reg, &script, timeout_ms)?;
+    }
+    Ok(StepResult {
+        script,
+        closing,
+        is_query: true,
+        next_arg,
+        needs_activities: opt.activity_by_name,
+    })
+}
+
+/// Options of the `search` step. Each matched field has its own pattern; the
+/// command line uses the same pattern for all of them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct SearchOptions {
+    pub debug: bool,
+    pub match_class: bool,
+    pub match_classname: bool,
+    pub match_role: bool,
+    pub match_name: bool,
+    pub match_pid: bool,
+    pub match_id: bool,
+    pub match_desktopfile: bool,
+    pub class_pattern: String,
+    pub classname_pattern: String,
+    pub role_pattern: String,
+    pub name_pattern: String,
+    pub id_pattern: String,
+    pub desktopfile_pattern: String,
+    pub pid: i32,
+    pub match_desktop: bool,
+    /// Desktop number, id or name.
+    pub desktop: String,
+    pub match_activity: bool,
+    /// Activity id or name, or "current".
+    pub activity: String,
+    /// `activity` is a name, to look up in `activity_list`.
+    pub activity_by_name: bool,
+    /// KWin window type properties, e.g. `normalWindow`. A window matches if
+    /// it has any of them.
+    pub window_types: Vec<String>,
+    pub match_screen: bool,
+    /// Output name (e.g. `DP-1`) or index in `workspace.screens`.
+    pub screen: String,
+    pub only_visible: bool,
+    pub match_minimized: bool,
+    pub minimized: bool,
+    pub fullscreen: bool,
+    pub above: bool,
+    pub normal_only: bool,
+    /// JavaScript compiled from a `--where` expression, see `compile_where`.
+    pub where_expr: String,
+    /// Key to sort the matches by, one of `SORT_KEYS`. Empty keeps the order
+    /// of `workspace.windowList()`.
+    pub sort: String,
+    pub reverse: bool,
+    pub limit: u32,
+    pub match_all: bool,
+    pub match_case: bool,
+    pub search_term: String,
+    /// Wait until a window matches, see `wrap_sync`.
+    pub sync: bool,
+    /// Search the window stack instead of all windows.
+    pub from_stack: bool,
+}
+
+/// The desktop `set_desktop` and `set_desktop_for_window` move to: either a
+/// desktop, or one relative to the current desktop.
+#[derive(Debug, Default)]
+struct DesktopTarget {
+    /// A desktop, or with `relative` the number of desktops to move.
+    desktop: Option<String>,
+    relative: bool,
+    /// Direction in the desktop grid: left, right, up or down.
+    direction: Option<String>,
+    /// Wrap around at the first and last desktop, or the grid edges.
+    wrap: bool,
+}
+
+impl DesktopTarget {
+    /// Validates the target, and adds it to `render_context`. With
+    /// `specials`, the desktop can also be "current_desktop" or "all".
+    fn add_context(
+        self,
+        render_context: &mut handlebars::Context,
+        specials: bool,
+    ) -> anyhow::Result<()> {
+        let mut desktop = serde_json::Value::from("");
+        let mut delta = 0;
+        if let Some(direction) = &self.direction {
+            if self.relative || self.desktop.is_some() {
+                return Err(anyhow!(
+                    "--direction can't be used with --relative or a desktop"
+                ));
+            }
+            if !["left", "right", "up", "down"].contains(&direction.as_str()) {
+                return Err(anyhow!(
+                    "invalid direction '{direction}', expected left, right, up or down"
+                ));
+            }
+        } else {
+            let arg = self.desktop.ok_or(anyhow!("missing argument 'desktop'"))?;
+            if self.relative {
+                delta = arg
+                    .parse::<i32>()
+                    .map_err(|_| anyhow!("invalid relative desktop '{arg}'"))?;
+            } else if self.wrap {
+                return Err(anyhow!("--wrap requires --relative or --direction"));
+            } else if specials && arg.eq_ignore_ascii_case("current_desktop") {
+                desktop = (-1).into();
+            } else if specials && arg.eq_ignore_ascii_case("all") {
+                desktop = (-2).into();
+            } else {
+                desktop = arg.into();
+            }
+        }
+        add_context(render_context, "desktop", desktop);
+        add_context(render_context, "relative", self.relative);
+        add_context(render_context, "delta", delta);
+        add_context(
+            render_context,
+            "direction",
+            self.direction.unwrap_or_default(),
+        );
+        add_context(render_context, "wrap", self.wrap);
+        Ok(())
+    }
+}
+
+/// Commands that use `activity_list`, see `StepResult::needs_activities`.
+const ACTIVITY_COMMANDS: &[&str] = &[
+    "get_activity",
+    "set_activity",
+    "list_activities",
+    "set_activities_for_window",
+];
+
+/// Whether `s` looks like a UUID, e.g. an activity id.
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36
+        && s.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// Commands of a chain that are not in `WINDOW_ACTIONS` or `GLOBAL_ACTIONS`.
+const CHAIN_COMMANDS: &[&str] = &[
+    "search",
+    "getactivewindow",
+    "savewindowstack",
+    "loadwindowstack",
+    "stackfilter",
+    "stackunion",
+    "stackintersect",
+    "stackdiff",
+    "stackslice",
+    "stackcount",
+    "if-empty",
+    "else",
+    "end",
+    "foreach",
+    "exit-if-empty",
+    "behave",
+    "watch",
+];
+
+fn is_command(name: &str) -> bool {
+    CHAIN_COMMANDS.contains(&name)
+        || WINDOW_ACTIONS.contains_key(name)
+        || GLOBAL_ACTIONS.contains_key(name)
+}
+
+/// Wraps a step with `--sync`, which pushes the conditions to wait for to
+/// `sync_pending`. Returns the script and its closing, as the rest of the
+/// chain runs in a callback.
+fn wrap_sync(
+    reg: &handlebars::Handlebars,
+    step_script: &str,
+    timeout_ms: u64,
+) -> anyhow::Result<(String, String)> {
+    let script = reg.render_template(
+        STEP_SYNC_WAIT,
+        &serde_json::json!({ "step": step_script, "timeout_ms": timeout_ms }),
+    )?;
+    Ok((script, STEP_SYNC_CLOSING.into()))
+}
+
+pub(crate) fn render_search(
+    reg: &handlebars::Handlebars,
+    opt: &SearchOptions,
+) -> anyhow::Result<String> {
+    let render_context = handlebars::Context::wraps(opt)?;
+    Ok(reg.render_template_with_context(STEP_SEARCH, &render_context)?)
+}
+
+/// Validates a sort key of `search --sort`.
+pub(crate) fn sort_key(key: &str) -> anyhow::Result<&'static str> {
+    SORT_KEYS
+        .iter()
+        .find(|k| k.eq_ignore_ascii_case(key))
+        .copied()
+        .ok_or_else(|| {
+            anyhow!(
+                "unknown sort key '{key}', expected one of: {}",
+                SORT_KEYS.join(", ")
+            )
+        })
+}
+
+/// Maps a window type name of `search --type` to the KWin property that is
+/// true for windows of that type.
+pub(crate) fn window_type_property(name: &str) -> anyhow::Result<&'static str> {
+    WINDOW_TYPES
+        .get(name.to_lowercase().as_str())
+        .copied()
+        .ok_or_else(|| {
+            let mut names: Vec<_> = WINDOW_TYPES.keys().copied().collect();
+            names.sort();
+            anyhow!(
+                "unknown window type '{name}', expected one of: {}",
+                names.join(", ")
+            )
+        })
+}
+
+/// Escapes `text` so that it matches literally when used as a regular
+/// expression.
+pub(crate) fn regex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Compiles a `--format` string into a JavaScript expression over the window
+/// `w`. `{field}` is replaced by a field from `WINDOW_FIELDS`, `{{` and `}}`
+/// by literal braces, and `\t`, `\n` and `\\` are unescaped.
+pub(crate) fn format_script(format: &str) -> anyhow::Result<String> {
+    let mut script = String::from("\"\"");
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => literal.push('\t'),
+                Some('n') => literal.push('\n'),
+                Some('\\') => literal.push('\\'),
+                Some(c) => {
+                    literal.push('\\');
+                    literal.push(c);
+                }
+                None => literal.push('\\'),
+            },
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(anyhow!("unterminated field in format '{format}'")),
+                    }
+                }
+                let expr = WINDOW_FIELDS
+                    .get(name.as_str())
+                    .ok_or_else(|| anyhow!("unknown format field '{name}'"))?;
+                if !literal.is_empty() {
+                    script.push_str(" + ");
+                    script.push_str(&js_literal(&std::mem::take(&mut literal).into()));
+                }
+                script.push_str(&format!(" + ({expr})"));
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        script.push_str(" + ");
+        script.push_str(&js_literal(&literal.into()));
+    }
+    Ok(script)
+}
+
+/// Generates the JavaScript for one `windowstate --add|--remove|--toggle`
+/// option, to be inserted into the `windowstate` action.
+pub(crate) fn windowstate_script(op: &str, property: &str) -> anyhow::Result<String> {
+    let key = property.to_lowercase();
+    let js = if let Some(prop) = WINDOWSTATE_PROPERTIES.get(&key) {
+        match op {
+            "add" => format!("w.{prop} = true; "),
+            "remove" => format!("w.{prop} = false; "),
+            "toggle" => format!("w.{prop} = !w.{prop}; "),
+            _ => unreachable!(),
+        }
+    } else if key == "maximized_vert" {
+        let new_state = match op {
+            "add" => "true",
+            "remove" => "false",
+            "toggle" => "!maximizeVert",
+            _ => unreachable!(),
+        };
+        format!("maximizeVert = {new_state};")
+    } else if key == "maximized_horz" {
+        let new_state = match op {
+            "add" => "true",
+            "remove" => "false",
+            "toggle" => "!maximizeHorz",
+            _ => unreachable!(),
+        };
+        format!("maximizeHorz = {new_state};")
+    } else if key == "maximized" {
+        let new_state = match op {
+            "add" => "true",
+            "remove" => "false",
+            "toggle" => "!(maximizeVert && maximizeHorz)",
+            _ => unreachable!(),
+        };
+        format!("let t = {new_state}; maximizeVert = maximizeHorz = t; ")
+    } else {
+        return Err(anyhow!("unsupported property '{key}'"));
+    };
+    Ok(js)
+}
+
+fn generate_kwinscript(globals: &Globals, mut parser: Parser) -> anyhow::Result<String> {
+    use lexopt::prelude::*;
+
+    let mut file_path: Option<String> = None;
+    let mut inline: Option<String> = None;
+
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("file") => {
+                file_path = Some(parser.value()?.string()?);
+            }
+            Long("inline") => {
+                inline = Some(parser.value()?.string()?);
+            }
+            _ => return Err(arg.unexpected().into()),
+        }
+    }
+
+    let body = match (file_path, inline) {
+        (Some(path), None) => std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read script file '{path}'"))?,
+        (None, Some(text)) => text,
+        (Some(_), Some(_)) => {
+            return Err(anyhow!("--file and --inline are mutually exclusive"));
+        }
+        (None, None) => return Err(anyhow!("supply either --file or --inline")),
+    };
+
+    // Render through the same template system so the user's script gets
+    // the helper functions (output_result, output_error, output_debug)
+    // and the D-Bus callbacks work correctly.
+    let reg = new_registry();
+    let render_context = handlebars::Context::wraps(globals)?;
+    let mut script = String::new();
+    script.push_str(&reg.render_template_with_context(SCRIPT_HEADER, &render_context)?);
+    script.push_str("    try {\n");
+    script.push_str("        ");
+    script.push_str(&body.replace('\n', "\n        "));
+    script.push_str("\n    } catch(e) {\n");
+    script.push_str("        output_error(\"Script error: \" + e.toString());\n");
+    script.push_str("    }\n");
+    script.push_str(&reg.render_template_with_context(STEP_DONE, &render_context)?);
+    script.push_str(&reg.render_template_with_context(SCRIPT_FOOTER, &render_context)?);
+    Ok(script)
+}
+
+/// Generates a script consisting of the single step template `step`.
+/// `params` are added to the template context of the step.
+pub(crate) fn generate_step_script(
+    globals: &Globals,
+    step: &str,
+    params: &serde_json::Value,
+) -> anyhow::Result<String> {
+    let mut full_script = String::new();
+    let reg = new_registry();
+    let render_context = handlebars::Context::wraps(globals)?;
+
+    full_script.push_str(&reg.render_template_with_context(SCRIPT_HEADER, &render_context)?);
+    let mut step_context = render_context.clone();
+    if let Some(params) = params.as_object() {
+        let data = step_context.data_mut().as_object_mut().unwrap();
+        data.extend(params.clone());
+    }
+    full_script.push_str(&reg.render_template_with_context(step, &step_context)?);
+    full_script.push_str(&reg.render_template_with_context(STEP_DONE, &render_context)?);
+    full_script.push_str(&reg.render_template_with_context(SCRIPT_FOOTER, &render_context)?);
+
+    Ok(full_script)
+}
+
+pub(crate) fn new_registry() -> handlebars::Handlebars<'static> {
+    let mut reg = handlebars::Handlebars::new();
+    reg.set_strict_mode(true);
+    reg.register_helper("js", Box::new(js_helper));
+    reg
+}
+
+/// `{{{js value}}}` renders `value` as a JavaScript literal. Every value that
+/// may come from the user must be inserted into a template this way.
+fn js_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let param = h
+        .param(0)
+        .ok_or(handlebars::RenderErrorReason::ParamNotFoundForIndex(
+            "js", 0,
+        ))?;
+    out.write(&js_literal(param.value()))?;
+    Ok(())
+}
+
+/// Encodes `value` as a JavaScript literal. JSON is valid JavaScript, except
+/// that older engines don't allow U+2028 and U+2029 in string literals.
+pub(crate) fn js_literal(value: &serde_json::Value) -> String {
+    serde_json::to_string(value)
+        .unwrap()
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029")
+}
+
+/// Parses a window stack selection like `%1` or `%-1` (the last window) into
+/// its index. `%@` (all windows) has to be handled by the caller.
+fn parse_stack_index(selection: &str) -> anyhow::Result<i32> {
+    match selection.strip_prefix('%').map(str::parse::<i32>) {
+        Some(Ok(index)) if index != 0 => Ok(index),
+        _ => Err(anyhow!(
+            "invalid window stack selection '{selection}', expected %N, %-N or %@"
+        )),
+    }
+}
+
+pub fn check_session() -> anyhow::Result<()> {
+    if std::env::var("KDE_SESSION_VERSION") != Ok("6".to_string()) {
+        return Err(Error::UnsupportedSession.into());
+    }
+    Ok(())
+}
+
+/// What a running script sent over D-Bus.
+enum Received {
+    Message(Message),
+    /// `output_done()`, with the number of messages sent before it.
+    Done(usize),
+    /// The script waits for up to this many milliseconds, e.g. for `--sync`.
+    Wait(u64),
+    /// `output_attached()`: the script keeps running, e.g. for `behave`.
+    Attached,
+}
+
+/// Collects the messages of a running script until it is done. The "done"
+/// message may overtake the others, as KWin's callDBus is asynchronous, so
+/// the script is complete once as many messages as it reported are here.
+/// A script that is attached is never complete.
+#[derive(Debug, Default)]
+struct Collector {
+    received: usize,
+    expected: Option<usize>,
+    attached: bool,
+}
+
+impl Collector {
+    /// Returns the message to pass on, if `received` is one.
+    fn receive(&mut self, received: Received) -> Option<Message> {
+        match received {
+            Received::Message(message) => {
+                self.received += 1;
+                return Some(message);
+            }
+            Received::Done(count) if !self.attached => self.expected = Some(count),
+            Received::Done(_) | Received::Wait(_) => {}
+            Received::Attached => {
+                self.attached = true;
+                self.expected = None;
+            }
+        }
+        None
+    }
+
+    fn is_complete(&self) -> bool {
+        self.expected.is_some_and(|n| self.received >= n)
+    }
+}
+
+/// Stops the script and the message receiver when dropped, so that a failed
+/// wait doesn't leave the script loaded in KWin.
+struct Cleanup<'a> {
+    self_conn: &'a SyncConnection,
+    receiver: dbus::channel::Token,
+    kwin_proxy: &'a dbus::blocking::Proxy<'a, &'a Connection>,
+    /// None if the script stays loaded, e.g. for `--shortcut` or `--name`.
+    script_proxy: Option<&'a dbus::blocking::Proxy<'a, &'a Connection>>,
+    script_name: &'a str,
+    /// Attached scripts (`behave`, `watch`) are unloaded rather than stopped.
+    attached: bool,
+}
+
+impl Drop for Cleanup<'_> {
+    fn drop(&mut self) {
+        if let Some(script_proxy) = self.script_proxy {
+            let result: Result<(), dbus::Error> = if self.attached {
+                self.kwin_proxy.method_call(
+                    "org.kde.kwin.Scripting",
+                    "unloadScript",
+                    (self.script_name,),
+                )
+            } else {
+                script_proxy.method_call("org.kde.kwin.Script", "stop", ())
+            };
+            if let Err(err) = result {
+                log::debug!("Failed to stop the script: {err}");
+            }
+        }
+        self.self_conn.stop_receive(self.receiver);
+    }
+}
+
+/// Parses a `--timeout` value in seconds, returning it in milliseconds.
+pub fn parse_timeout(seconds: &str) -> anyhow::Result<u64> {
+    match seconds.parse::<f64>() {
+        Ok(s) if s.is_finite() && s > 0.0 => Ok((s * 1000.0).ceil() as u64),
+        _ => Err(anyhow!(
+            "invalid timeout '{seconds}', expected a positive number of seconds"
+        )),
+    }
+}
+
+/// Loads `script_contents` into KWin, runs it, and returns the messages it
+/// sent back once it is done. Fails with [`Error::Timeout`] if that takes
+/// longer than `globals.timeout_ms`. `self_conn` must be the connection
+/// `globals.dbus_addr` belongs to.
+///
+/// With `globals.shortcut` set the script stays loaded, waiting for the
+/// shortcut; in that case no messages are expected.
+pub fn run_script(
+    script_contents: &str,
+    globals: &Globals,
+    self_conn: SyncConnection,
+) -> anyhow::Result<Vec<Message>> {
+    let mut messages = vec![];
+    run_script_with(
+        script_contents,
+        globals,
+        self_conn,
+        &AtomicBool::new(false),
+        |message| messages.push(message),
+    )?;
+    Ok(messages)
+}
+
+/// Like [`run_script`], but passes each message to `on_message` as soon as it
+/// arrives. Returns the id KWin gave the script.
+///
+/// Scripts that keep running (e.g. `behave`) are stopped once `stop` is set,
+/// or left loaded right away if [`Globals::detach`] is set.
+pub fn run_script_with(
+    script_contents: &str,
+    globals: &Globals,
+    self_conn: SyncConnection,
+    stop: &AtomicBool,
+    mut on_message: impl FnMut(Message),
+) -> anyhow::Result<i32> {
+    let kwin_conn = Connection::new_session()?;
+    let kwin_proxy =
+        kwin_conn.with_proxy("org.kde.KWin", "/Scripting", Duration::from_millis(5000));
+
+    let mut script_file = tempfile::NamedTempFile::with_prefix("kdotool-")?;
+    script_file.write_all(script_contents.as_bytes())?;
+    let script_file_path = script_file.into_temp_path();
+
+    log::debug!("===== Load script into KWin =====");
+    let script_id: i32;
+    (script_id,) = kwin_proxy.method_call(
+        "org.kde.kwin.Scripting",
+        "loadScript",
+        (script_file_path.to_str().unwrap(), &globals.script_name),
+    )?;
+    if script_id < 0 {
+        return Err(anyhow!(
+            "Failed to load script. A script with the same name may already exist. Please use `--remove` to remove it first."
+        ));
+    }
+
+    log::debug!("Script ID: {script_id}");
+    log::debug!("Script name: {}", globals.script_name);
+
+    log::debug!("===== Run script =====");
+    let script_proxy = kwin_conn.with_proxy(
+        "org.kde.KWin",
+        format!("/Scripting/Script{script_id}"),
+        Duration::from_millis(5000),
+    );
+
+    // setup message receiver
+    let (tx, rx) = std::sync::mpsc::channel();
+    let receiver = self_conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, _connection| -> bool {
+            log::debug!("dbus message: {:?}", message);
+            if let Some(member) = message.member()
+                && let Some(arg) = message.get1::<String>()
+            {
+                let received = match member.as_ref() {
+                    "result" => Received::Message(Message::Result(arg)),
+                    "error" => Received::Message(Message::Error(arg)),
+                    "done" => Received::Done(arg.parse().unwrap_or(0)),
+                    "wait" => Received::Wait(arg.parse().unwrap_or(0)),
+                    "attached" => Received::Attached,
+                    _ => Received::Message(Message::Debug(arg)),
+                };
+                let _ = tx.send(received);
+            }
+            true
+        }),
+    );
+    // From here on, the script must be stopped however we leave.
+    let mut cleanup = Cleanup {
+        self_conn: &self_conn,
+        receiver,
+        kwin_proxy: &kwin_proxy,
+        script_proxy: globals.shortcut.is_empty().then_some(&script_proxy),
+        script_name: &globals.script_name,
+        attached: false,
+    };
+
+    let start_time = chrono::Local::now();
+    let _: () = script_proxy.method_call("org.kde.kwin.Script", "run", ())?;
+
+    // The script reports "done" with the number of messages it has sent.
+    // KWin's callDBus is asynchronous, so wait until all of them are here.
+    // Scripts that keep running report "attached" instead, and are followed
+    // until `stop` is set.
+    let mut result = Ok(());
+    if globals.shortcut.is_empty() {
+        let mut deadline = Some(Instant::now() + Duration::from_millis(globals.timeout_ms));
+        let mut collector = Collector::default();
+        while !collector.is_complete() {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                log::debug!("Timed out, received {} message(s)", collector.received);
+                result = Err(Error::Timeout);
+                break;
+            }
+            self_conn.process(Duration::from_millis(100))?;
+            for received in rx.try_iter() {
+                if let Received::Wait(ms) = received
+                    && !collector.attached
+                {
+                    // The script waits for something, e.g. --sync. Give the
+                    // rest of it the usual timeout after that.
+                    deadline =
+                        Some(Instant::now() + Duration::from_millis(ms + globals.timeout_ms));
+                }
+                if let Some(message) = collector.receive(received) {
+                    on_message(message);
+                }
+            }
+            if collector.attached {
+                deadline = None;
+                cleanup.attached = true;
+                if globals.detach {
+                    cleanup.script_proxy = None;
+                    break;
+                }
+            }
+        }
+    }
+    drop(cleanup);
+
+    if globals.debug {
+        log_kwin_journal(start_time);
+    }
+
+    result?;
+    Ok(script_id)
+}
+
+fn log_kwin_journal(start_time: chrono::DateTime<chrono::Local>) {
+    if let Ok(journal) = Command::new("journalctl")
+        .arg(format!(
+            "--since={}",
+            start_time.format("%Y-%m-%d %H:%M:%S")
+        ))
+        .arg("--user")
+        .arg("--user-unit=plasma-kwin_wayland.service")
+        .arg("--user-unit=plasma-kwin_x11.service")
+        .arg("QT_CATEGORY=js")
+        .arg("QT_CATEGORY=kwin_scripting")
+        .arg("--output=cat")
+        .output()
+    {
+        let output = String::from_utf8_lossy(&journal.stdout);
+        log::debug!("KWin log from the systemd journal:\n{}", output.trim_end());
+    } else {
+        log::debug!("Failed getting KWin log from the systemd journal.");
+    }
+}
+
+/// Removes a script previously registered with `--shortcut --name NAME`.
+pub fn unload_script(script_name: &str) -> anyhow::Result<()> {
+    let kwin_conn = Connection::new_session()?;
+    let kwin_proxy =
+        kwin_conn.with_proxy("org.kde.KWin", "/Scripting", Duration::from_millis(5000));
+    let _: () = kwin_proxy.method_call("org.kde.kwin.Scripting", "unloadScript", (script_name,))?;
+    Ok(())
+}
+
+/// Runs a command chain, with the same syntax and semantics as the `kdotool`
+/// command line (without the global options), and returns the messages the
+/// script sent back.
+///
+/// ```no_run
+/// let messages = kdotool::engine::run(["search", "--class", "konsole", "windowactivate"])?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn run<I>(args: I) -> anyhow::Result<Vec<Message>>
+where
+    I: IntoIterator,
+    I::Item: Into<std::ffi::OsString>,
+{
+    use lexopt::prelude::*;
+
+    check_session()?;
+
+    let mut parser = Parser::from_args(args);
+    let command = match parser.next()? {
+        Some(Value(val)) => val.string()?,
+        Some(arg) => return Err(arg.unexpected().into()),
+        None => return Err(anyhow!("missing command")),
+    };
+
+    let mut globals = Globals::new(&format!("kdotool::engine::run {command}"))?;
+    let self_conn = SyncConnection::new_session()?;
+    globals.dbus_addr = self_conn.unique_name().to_string();
+
+    let script_contents = generate_script(&globals, parser, &command)?;
+    run_script(&script_contents, &globals, self_conn)
+}
+
+/// Runs the single step template `step` and returns the first result it
+/// reports.
+pub(crate) fn run_step(
+    cmdline: &str,
+    step: &str,
+    params: &serde_json::Value,
+) -> anyhow::Result<String> {
+    check_session()?;
+
+    let mut globals = Globals::new(cmdline)?;
+    // Establish the DBus listener connection first so we know the address
+    // to embed in the generated KWin script.
+    let self_conn = SyncConnection::new_session()?;
+    globals.dbus_addr = self_conn.unique_name().to_string();
+
+    let script_contents = generate_step_script(&globals, step, params)?;
+    let mut result = None;
+    for message in run_script(&script_contents, &globals, self_conn)? {
+        match message {
+            Message::Error(message) => return Err(Error::Script(message).into()),
+            Message::Result(payload) if result.is_none() => result = Some(payload),
+            _ => {}
+        }
+    }
+    result.ok_or_else(|| anyhow!("the script returned no result"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn js_literal_escapes_strings() {
+        let cases = [
+            ("plain", r#""plain""#),
+            ("back`tick", r#""back`tick""#),
+            ("${alert(1)}", r#""${alert(1)}""#),
+            (r#"say "hi""#, r#""say \"hi\"""#),
+            (r"C:\dir\d+", r#""C:\\dir\\d+""#),
+            ("line\nbreak\u{2028}", r#""line\nbreak\u2028""#),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(js_literal(&serde_json::Value::from(input)), expected);
+        }
+    }
+
+    #[test]
+    fn user_input_cannot_escape_string_literals() {
+        let pattern = r#"`); output_result("pwned"); (`${x}"\"#;
+        let script = render_search(
+            &new_registry(),
+            &SearchOptions {
+                match_name: true,
+                name_pattern: pattern.into(),
+                search_term: pattern.into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let literal = js_literal(&serde_json::Value::from(pattern));
+        assert!(script.contains(&format!("new RegExp({literal}, re_opts)")));
+        assert!(script.contains(&format!(r#""STEP search " + {literal}"#)));
+        assert!(!script.replace(&literal, "").contains("pwned"));
+    }
+
+    #[test]
+    fn collector_waits_for_late_messages() {
+        let result = || Received::Message(Message::Result("x".into()));
+
+        // "done" overtakes the messages it counts.
+        let mut collector = Collector::default();
+        assert!(collector.receive(result()).is_some());
+        assert!(!collector.is_complete());
+        assert!(collector.receive(Received::Done(3)).is_none());
+        assert!(!collector.is_complete());
+        collector.receive(Received::Message(Message::Debug("d".into())));
+        assert!(!collector.is_complete());
+        collector.receive(result());
+        assert!(collector.is_complete());
+        assert_eq!(collector.received, 3);
+
+        // Messages alone never complete the script.
+        let mut collector = Collector::default();
+        for _ in 0..10 {
+            collector.receive(result());
+        }
+        assert!(!collector.is_complete());
+
+        let mut collector = Collector::default();
+        collector.receive(Received::Done(0));
+        assert!(collector.is_complete());
+
+        // "wait" (e.g. --sync) neither counts as a message nor completes.
+        let mut collector = Collector::default();
+        assert!(collector.receive(Received::Wait(500)).is_none());
+        collector.receive(Received::Done(1));
+        assert!(!collector.is_complete());
+        collector.receive(result());
+        assert!(collector.is_complete());
+
+        // An attached script keeps running, whatever "done" it sent before
+        // or after attaching.
+        let mut collector = Collector::default();
+        collector.receive(Received::Done(1));
+        collector.receive(Received::Attached);
+        collector.receive(result());
+        collector.receive(Received::Done(1));
+        assert!(collector.attached);
+        assert!(!collector.is_complete());
+    }
+
+    #[test]
+    fn timeout_must_be_positive() {
+        assert_eq!(parse_timeout("2.5").unwrap(), 2500);
+        assert_eq!(parse_timeout("0.0001").unwrap(), 1);
+        for value in ["0", "-1", "NaN", "inf", "soon"] {
+            assert!(parse_timeout(value).is_err(), "{value}");
+        }
+
+        let parser = Parser::from_args(["--sync", "--timeout", "0", "firefox"]);
+        assert!(generate_script(&Globals::default(), parser, "search").is_err());
+    }
+
+    #[test]
+    fn behave_runs_rest_of_chain_as_handler() {
+        let parser = Parser::from_args(["added", "getwindowname"]);
+        let script = generate_script(&Globals::default(), parser, "behave").unwrap();
+        assert!(script.contains("workspace.windowAdded.connect(behave_run);"));
+        assert!(script.contains("output_attached();"));
+        assert!(!script.contains("\n    output_done();"));
+
+        let parser = Parser::from_args(["%1", "added", "getwindowname"]);
+        assert!(generate_script(&Globals::default(), parser, "behave").is_err());
+
+        let parser = Parser::from_args(["%@", "caption", "getwindowname"]);
+        let script = generate_script(&Globals::default(), parser, "behave").unwrap();
+        assert!(script.contains("return window_stack.map("));
+    }
+
+    #[test]
+    fn sync_continues_chain_in_callback() {
+        let parser = Parser::from_args(["--sync", "%1", "0", "0", "getwindowname"]);
+        let script = generate_script(&Globals::default(), parser, "windowmove").unwrap();
+        let wait = script.find("sync_wait(sync_pending, ").unwrap();
+        let name = script.find("STEP getwindowname").unwrap();
+        let done = script.find(STEP_DONE).unwrap();
+        let closing = script.find(STEP_SYNC_CLOSING).unwrap();
+        assert!(wait < name && name < done && done < closing);
+    }
+
+    #[test]
+    fn search_exact_matches_literal_term() {
+        let parser = Parser::from_args(["--exact", "--desktopfile", "org.kde.dolphin"]);
+        let script = generate_script(&Globals::default(), parser, "search").unwrap();
+        assert!(script.contains(r#"new RegExp("^org\\.kde\\.dolphin$", re_opts)"#));
+        assert!(script.contains("w.desktopFileName.search(re_desktopfile)"));
+        assert!(!script.contains("re_class"));
+    }
+
+    #[test]
+    fn stackfilter_searches_window_stack() {
+        let parser = Parser::from_args(["--where", "above", "stackslice", "2", "getwindowname"]);
+        let script = generate_script(&Globals::default(), parser, "stackfilter").unwrap();
+        assert!(script.contains("var t = window_stack.slice();"));
+        assert!(script.contains("if (!(Boolean(w.keepAbove))) continue;"));
+        assert!(script.contains("const start = 2 > 0 ? 2 - 1"));
+        assert!(script.contains("const end = -1 > 0 ? -1"));
+        assert!(script.contains("STEP getwindowname"));
+    }
+
+    #[test]
+    fn control_blocks_nest() {
+        let parser = Parser::from_args([
+            "foreach",
+            "if-empty",
+            "getactivewindow",
+            "else",
+            "windowraise",
+            "end",
+            "end",
+            "exit-if-empty",
+            "getwindowname",
+        ]);
+        let script = generate_script(&Globals::default(), parser, "getactivewindow").unwrap();
+        let mut rest = &script[..];
+        for step in [
+            "STEP foreach",
+            "STEP if-empty",
+            "STEP getactivewindow",
+            "} else {",
+            "STEP windowraise",
+            "window_stack = foreach_stack;",
+        ] {
+            rest = &rest[rest.find(step).unwrap()..];
+        }
+        let exit = &script[script.find("STEP exit-if-empty").unwrap()..];
+        assert!(exit[..exit.find("return;").unwrap()].contains("output_done();"));
+
+        let parser = Parser::from_args(["activated", "exit-if-empty", "getwindowname"]);
+        let script = generate_script(&Globals::default(), parser, "behave").unwrap();
+        let handler = &script[script.find("STEP exit-if-empty").unwrap()..];
+        assert!(!handler[..handler.find("return;").unwrap()].contains("output_done"));
+
+        for args in [
+            &["foreach"][..],
+            &["end"],
+            &["else"],
+            &["if-empty", "windowactivate", "--sync"],
+        ] {
+            assert!(
+                generate_script(
+                    &Globals::default(),
+                    Parser::from_args(args),
+                    "getactivewindow"
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn desktop_create_position_is_optional() {
+        let parser = Parser::from_args(["Mail", "list_desktops"]);
+        let script = generate_script(&Globals::default(), parser, "desktop_create").unwrap();
+        assert!(script.contains("const position = 0 > 0"));
+        assert!(script.contains(r#"workspace.desktops.length), "Mail");"#));
+        assert!(script.contains("desktop_info(d)"));
+
+        let parser = Parser::from_args(["Mail", "2"]);
+        let script = generate_script(&Globals::default(), parser, "desktop_create").unwrap();
+        assert!(script.contains("const position = 2 > 0 ? 2 - 1"));
+        for position in ["0", "-1"] {
+            let parser = Parser::from_args(["Mail", position]);
+            assert!(generate_script(&Globals::default(), parser, "desktop_create").is_err());
+        }
+    }
+
+    #[test]
+    fn desktops_are_found_by_number_id_or_name() {
+        let cases = [
+            ("all", "window_setDesktop(w, -2);"),
+            ("Current_Desktop", "window_setDesktop(w, -1);"),
+            ("2", r#"window_setDesktop(w, "2");"#),
+            ("Mail", r#"window_setDesktop(w, "Mail");"#),
+        ];
+        for (desktop, expected) in cases {
+            let parser = Parser::from_args([desktop]);
+            let script =
+                generate_script(&Globals::default(), parser, "set_desktop_for_window").unwrap();
+            assert!(script.contains(expected), "{desktop}");
+        }
+
+        let parser = Parser::from_args(["-D", "Mail", "."]);
+        let script = generate_script(&Globals::default(), parser, "search").unwrap();
+        assert!(script.contains(r#"const search_desktop = workspace_findDesktop("Mail");"#));
+    }
+
+    #[test]
+    fn relative_desktop_targets() {
+        let parser = Parser::from_args(["--relative", "-1", "--wrap"]);
+        let script = generate_script(&Globals::default(), parser, "set_desktop").unwrap();
+        assert!(script.contains("workspace_desktopRelative(workspace.currentDesktop, -1, true)"));
+
+        let parser = Parser::from_args(["--direction", "down", "getwindowname"]);
+        let script =
+            generate_script(&Globals::default(), parser, "set_desktop_for_window").unwrap();
+        assert!(script.contains(r#"w.desktops[0] || workspace.currentDesktop, "down", false)"#));
+        assert!(script.contains("STEP getwindowname"));
+
+        for args in [
+            &["--direction", "down", "--relative", "1"][..],
+            &["--wrap", "2"],
+            &["--relative", "next"],
+        ] {
+            assert!(
+                generate_script(&Globals::default(), Parser::from_args(args), "set_desktop")
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn window_desktop_membership() {
+        let parser =
+            Parser::from_args(["%@", "current_desktop", "get_desktop_for_window", "--all"]);
+        let script =
+            generate_script(&Globals::default(), parser, "add_desktop_for_window").unwrap();
+        assert!(script.contains("window_addDesktop(w, -1);"));
+        assert!(script.contains("window_desktops(w).map(desktop_output)"));
+
+        let parser = Parser::from_args(["Mail"]);
+        let script =
+            generate_script(&Globals::default(), parser, "remove_desktop_for_window").unwrap();
+        assert!(script.contains(r#"window_removeDesktop(w, "Mail");"#));
+    }
+
+    #[test]
+    fn activity_names_are_loaded_when_needed() {
+        let parser = Parser::from_args(["--activity", "current"]);
+        let script = generate_script(&Globals::default(), parser, "search").unwrap();
+        assert!(!script.contains("activities_load((activity_list)"));
+
+        let parser = Parser::from_args(["--activity", "b2b2b2b2-0000-0000-0000-000000000002"]);
+        let script = generate_script(&Globals::default(), parser, "search").unwrap();
+        assert!(!script.contains("activities_load((activity_list)"));
+
+        let parser = Parser::from_args([
+            "--activity",
+            "Work",
+            "konsole",
+            "set_activities_for_window",
+            "all",
+        ]);
+        let script = generate_script(&Globals::default(), parser, "search").unwrap();
+        assert!(script.contains("activities_load((activity_list)"));
+        assert!(script.contains(r#"activities_find(activity_list, "Work")"#));
+        assert!(script.contains(r#"window_setActivities(w, activity_list, ["all"]);"#));
+
+        let parser = Parser::from_args(["activated", "set_activity", "Work"]);
+        let script = generate_script(&Globals::default(), parser, "behave").unwrap();
+        assert!(script.contains("activities_load((activity_list)"));
+    }
+
+    #[test]
+    fn format_script_substitutes_fields() {
+        assert_eq!(
+            format_script(r"{id}\t{x},{y} {{{class}}}").unwrap(),
+            r#""" + (w.internalId) + "\t" + (w.x) + "," + (w.y) + " {" + (w.resourceClass) + "}""#
+        );
+        assert!(format_script("{nope}").is_err());
+        assert!(format_script("{id").is_err());
+    }
+
+    #[test]
+    fn stack_selection_starts_at_one() {
+        assert_eq!(parse_stack_index("%1").unwrap(), 1);
+        assert_eq!(parse_stack_index("%-1").unwrap(), -1);
+        for selection in ["%0", "%-0", "%", "%x", "1"] {
+            assert!(parse_stack_index(selection).is_err(), "{selection}");
+        }
+
+        let parser = Parser::from_args(["%0", "caption", "getwindowname"]);
+        assert!(generate_script(&Globals::default(), parser, "behave").is_err());
+        let parser = Parser::from_args(["%0"]);
+        assert!(generate_script(&Globals::default(), parser, "windowraise").is_err());
+    }
+}
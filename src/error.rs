@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// Errors returned by the library API.
+#[derive(Debug)]
+pub enum Error {
+    /// The current session is not KDE Plasma 6.
+    UnsupportedSession,
+    /// No window with the given KWin internal id exists.
+    WindowNotFound(String),
+    /// The generated KWin script reported an error.
+    Script(String),
+    /// KWin did not answer in time.
+    Timeout,
+    /// Talking to KWin over D-Bus failed.
+    DBus(dbus::Error),
+    /// Any other failure, e.g. I/O or an unparsable response.
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnsupportedSession => write!(
+                f,
+                "Unsupported KDE version. kdotool only supports KDE Plasma 6."
+            ),
+            Error::WindowNotFound(id) => write!(f, "Window not found: {id}"),
+            Error::Script(message) => write!(f, "KWin script error: {message}"),
+            Error::Timeout => write!(f, "Timed out waiting for KWin response"),
+            Error::DBus(err) => write!(f, "D-Bus error: {err}"),
+            Error::Other(err) => write!(f, "{err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DBus(err) => Some(err),
+            Error::Other(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<dbus::Error> for Error {
+    fn from(err: dbus::Error) -> Self {
+        Error::DBus(err)
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    // Internal code uses anyhow throughout and wraps the structured variants
+    // in it, so unwrap them again at the API boundary.
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast::<dbus::Error>() {
+                Ok(err) => Error::DBus(err),
+                Err(err) => Error::Other(err),
+            },
+        }
+    }
+}
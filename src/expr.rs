@@ -0,0 +1,367 @@
+//! The expression language of `search --where`.
+//!
+//! ```text
+//! expr       := and ("||" and)*
+//! and        := unary ("&&" unary)*
+//! unary      := "!" unary | "(" expr ")" | comparison
+//! comparison := FIELD [OP VALUE]
+//! OP         := "~" | "!~" | "==" | "!=" | "<" | "<=" | ">" | ">="
+//! VALUE      := "string" | 'string' | NUMBER | true | false
+//! ```
+//!
+//! FIELD is one of `WINDOW_FIELDS`. `~` matches a regular expression, `==`
+//! compares literally, and the ordering operators compare numbers. A field
+//! without an operator is tested for truth, e.g. `minimized && !above`.
+//!
+//! Expressions are parsed here and compiled into a JavaScript expression over
+//! the window `w`, so that syntax errors, including those of `~` patterns, are
+//! reported before the script is loaded into KWin.
+
+use anyhow::anyhow;
+
+use crate::engine::js_literal;
+use crate::templates::WINDOW_FIELDS;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(String),
+    Str(String),
+    Number(f64),
+    Bool(bool),
+    Op(&'static str),
+    Not,
+    And,
+    Or,
+    LParen,
+    RParen,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Field(name) => format!("'{name}'"),
+            Token::Str(s) => format!("string {s:?}"),
+            Token::Number(n) => format!("number {n}"),
+            Token::Bool(b) => format!("'{b}'"),
+            Token::Op(op) => format!("'{op}'"),
+            Token::Not => "'!'".into(),
+            Token::And => "'&&'".into(),
+            Token::Or => "'||'".into(),
+            Token::LParen => "'('".into(),
+            Token::RParen => "')'".into(),
+        }
+    }
+}
+
+/// Splits `text` into tokens, each with its (character) position.
+fn tokenize(text: &str) -> anyhow::Result<Vec<(usize, Token)>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let token = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '~' => Token::Op("~"),
+            '&' if next == Some('&') => Token::And,
+            '|' if next == Some('|') => Token::Or,
+            '=' if next == Some('=') => Token::Op("=="),
+            '!' if next == Some('=') => Token::Op("!="),
+            '!' if next == Some('~') => Token::Op("!~"),
+            '!' => Token::Not,
+            '<' if next == Some('=') => Token::Op("<="),
+            '<' => Token::Op("<"),
+            '>' if next == Some('=') => Token::Op(">="),
+            '>' => Token::Op(">"),
+            '"' | '\'' => {
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('\\') if i + 1 < chars.len() => {
+                            s.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&q) if q == c => break,
+                        Some(&other) => {
+                            s.push(other);
+                            i += 1;
+                        }
+                        None => return Err(anyhow!("unterminated string at position {start}")),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                tokens.push((
+                    start,
+                    Token::Number(
+                        number.parse().map_err(|_| {
+                            anyhow!("invalid number '{number}' at position {start}")
+                        })?,
+                    ),
+                ));
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push((
+                    start,
+                    match word.as_str() {
+                        "true" => Token::Bool(true),
+                        "false" => Token::Bool(false),
+                        _ => Token::Field(word),
+                    },
+                ));
+                continue;
+            }
+            c => return Err(anyhow!("unexpected '{c}' at position {start}")),
+        };
+        i += match token {
+            Token::And | Token::Or => 2,
+            Token::Op(op) => op.len(),
+            _ => 1,
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    len: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, expected: &str) -> anyhow::Error {
+        match self.tokens.get(self.pos) {
+            Some((at, token)) => anyhow!(
+                "expected {expected} at position {at}, found {}",
+                token.describe()
+            ),
+            None => anyhow!("expected {expected} at position {}", self.len),
+        }
+    }
+
+    fn expr(&mut self) -> anyhow::Result<String> {
+        let mut js = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            js = format!("{js} || {}", self.and()?);
+        }
+        Ok(js)
+    }
+
+    fn and(&mut self) -> anyhow::Result<String> {
+        let mut js = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            js = format!("{js} && {}", self.unary()?);
+        }
+        Ok(js)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(format!("!{}", self.unary()?))
+            }
+            Some(Token::LParen) => {
+                self.next();
+                let js = self.expr()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(self.error("')'"));
+                }
+                self.next();
+                Ok(format!("({js})"))
+            }
+            Some(Token::Field(_)) => self.comparison(),
+            _ => Err(self.error("a field, '!' or '('")),
+        }
+    }
+
+    fn comparison(&mut self) -> anyhow::Result<String> {
+        let at = self.tokens[self.pos].0;
+        let Some(Token::Field(name)) = self.next() else {
+            unreachable!()
+        };
+        let field = WINDOW_FIELDS
+            .get(name.as_str())
+            .ok_or_else(|| anyhow!("unknown field '{name}' at position {at}"))?;
+        let Some(Token::Op(op)) = self.peek().cloned() else {
+            return Ok(format!("Boolean({field})"));
+        };
+        self.next();
+        let value_at = self.tokens.get(self.pos).map_or(self.len, |(at, _)| *at);
+        let value = match self.peek() {
+            Some(Token::Str(_) | Token::Number(_) | Token::Bool(_)) => self.next().unwrap(),
+            _ => return Err(self.error("a string, number or boolean")),
+        };
+        match (op, value) {
+            ("~" | "!~", Token::Str(pattern)) => {
+                check_regex(&pattern).map_err(|err| {
+                    anyhow!("invalid regular expression at position {value_at}: {err}")
+                })?;
+                let not = if op == "!~" { "!" } else { "" };
+                Ok(format!(
+                    "{not}(String({field}).search(new RegExp({}, re_opts)) >= 0)",
+                    js_literal(&pattern.into())
+                ))
+            }
+            ("~" | "!~", _) => {
+                self.pos -= 1;
+                Err(self.error("a string"))
+            }
+            ("==" | "!=", Token::Str(s)) => {
+                Ok(format!("(String({field}) {op} {})", js_literal(&s.into())))
+            }
+            ("==" | "!=", Token::Bool(b)) => Ok(format!("(Boolean({field}) {op} {b})")),
+            (_, Token::Number(n)) => Ok(format!("(Number({field}) {op} {n})")),
+            _ => {
+                self.pos -= 1;
+                Err(self.error("a number"))
+            }
+        }
+    }
+}
+
+/// Checks the syntax of a `~` pattern, so that mistakes like an unclosed group
+/// are reported before the script runs. The patterns are JavaScript regular
+/// expressions, so what the `regex` syntax lacks (look-around,
+/// back-references, other escapes) is left for KWin to check.
+fn check_regex(pattern: &str) -> Result<(), regex_syntax::ast::ErrorKind> {
+    use regex_syntax::ast::ErrorKind;
+
+    match regex_syntax::ast::parse::Parser::new().parse(pattern) {
+        Ok(_) => Ok(()),
+        Err(err) => match err.kind() {
+            ErrorKind::UnsupportedLookAround
+            | ErrorKind::UnsupportedBackreference
+            | ErrorKind::EscapeUnrecognized => Ok(()),
+            kind => Err(kind.clone()),
+        },
+    }
+}
+
+/// Compiles a `--where` expression into a JavaScript expression over the
+/// window `w`, using the `re_opts` of `STEP_SEARCH` for regular expressions.
+pub(crate) fn compile_where(text: &str) -> anyhow::Result<String> {
+    let tokens = tokenize(text).map_err(|err| anyhow!("invalid --where expression: {err}"))?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        len: text.chars().count(),
+    };
+    let js = parser
+        .expr()
+        .and_then(|js| match parser.peek() {
+            None => Ok(js),
+            Some(_) => Err(parser.error("'&&', '||' or end of expression")),
+        })
+        .map_err(|err| anyhow!("invalid --where expression: {err}"))?;
+    Ok(js)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_boolean_expressions() {
+        assert_eq!(
+            compile_where(r#"class~"firefox" && !(title~'Private')"#).unwrap(),
+            r#"(String(w.resourceClass).search(new RegExp("firefox", re_opts)) >= 0) && !((String(w.caption).search(new RegExp("Private", re_opts)) >= 0))"#
+        );
+        assert_eq!(
+            compile_where("desktop == 2 || minimized && width >= 800").unwrap(),
+            "(Number(window_x11DesktopIds(w)[0]) == 2) || Boolean(w.minimized) && (Number(w.width) >= 800)"
+        );
+        assert_eq!(
+            compile_where(r#"output != "DP-1""#).unwrap(),
+            r#"(String((w.output ? w.output.name : "")) != "DP-1")"#
+        );
+    }
+
+    #[test]
+    fn accepts_javascript_regex_features() {
+        for pattern in [
+            r"(?<=a)b",
+            r"(a)\1",
+            r"\k<x>",
+            r"a(?!b)",
+            r"\/path\/",
+            r"^\d+$",
+        ] {
+            let text = format!("title ~ {}", js_literal(&pattern.into()));
+            assert!(compile_where(&text).is_ok(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn strings_cannot_escape_literals() {
+        let js = compile_where(r#"title == "\"); output_result(1); (\"""#).unwrap();
+        assert_eq!(js, r#"(String(w.caption) == "\"); output_result(1); (\"")"#);
+    }
+
+    #[test]
+    fn reports_errors_with_position() {
+        let cases = [
+            (
+                "class ~",
+                "expected a string, number or boolean at position 7",
+            ),
+            ("(class~\"a\"", "expected ')' at position 10"),
+            ("colour == 1", "unknown field 'colour' at position 0"),
+            (
+                "title ~ 3",
+                "expected a string at position 8, found number 3",
+            ),
+            ("x > \"1\"", "expected a number at position 4"),
+            ("title ~ \"a", "unterminated string at position 8"),
+            (
+                "title ~ \"(a\"",
+                "invalid regular expression at position 8: unclosed group",
+            ),
+            (
+                "class !~ '[a-'",
+                "invalid regular expression at position 9: unclosed character class",
+            ),
+            (
+                "above above",
+                "expected '&&', '||' or end of expression at position 6",
+            ),
+            ("a & b", "unexpected '&' at position 2"),
+        ];
+        for (text, expected) in cases {
+            let err = compile_where(text).unwrap_err().to_string();
+            assert!(err.contains(expected), "{text}: {err}");
+        }
+    }
+}
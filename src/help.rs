@@ -17,11 +17,28 @@ Options:
     -q, --quiet        Don't print anything to stdout. Useful for scripting.
     -d, --debug        Enable debug output
     -n, --dry-run      Don't actually run the script. Just print it to stdout.
+    --timeout SECONDS  How long to wait for the script to finish (default: 5)
+    --json             Output query results as a single JSON array. Errors
+                       are reported as {{"error": "..."}} objects.
+    --jsonl            Like --json, but print one JSON object per line.
+    --format FORMAT    If the last command is a window query, output each
+                       window in the stack using FORMAT, e.g.
+                       '{{id}}\t{{class}}\t{{title}}'. Fields: id, title (or
+                       name), class, classname, role, desktopfile, pid, x,
+                       y, width, height, desktop, desktopname, desktopid,
+                       screen, output, minimized, fullscreen, above. Use {{{{
+                       and }}}} for literal braces.
+    --desktop-format number|name|id
+                       How get_desktop, get_desktop_for_window and watch
+                       output desktops (default: number).
 
     --shortcut SHORTCUT [--name NAME]
         Register a shortcut to run the script.
         Optionally set a name for the shortcut, so you can remove it later.
 
+    --name NAME
+        With behave, leave the script running in KWin under NAME and exit.
+
     --remove NAME    Remove a previously registered shortcut.
 
 Window Query Commands:
@@ -30,7 +47,8 @@ Window Query Commands:
         expression pattern.
 
         The default options are --title --class --classname --role (unless you
-        specify one or more of --title, --class, --classname, or --role).
+        specify one or more of --title, --class, --classname, --role, or
+        --desktopfile).
 
         OPTIONS:
         -C, --case-sensitive
@@ -44,13 +62,56 @@ Window Query Commands:
         -t, --title, --name
             Match against the window title. This is the same string that is
             displayed in the window titlebar.
+        --desktopfile
+            Match against the desktop file name of the window's application,
+            e.g. org.kde.dolphin. This is often more reliable than the class
+            for Wayland applications.
+        --exact
+            Match windows whose fields are exactly PATTERN, instead of
+            treating it as a regular expression.
         -p, --pid PID
             Match windows that belong to a specific process id. This may not
             work for some X applications that do not set this metadata on its
             windows.
-        -D, --desktop NUMBER
+        -D, --desktop DESKTOP
             Only match windows on a certain desktop. The default is to search
             all desktops.
+        --activity ACTIVITY
+            Only match windows on a certain activity, given by its id or name,
+            or 'current'.
+        --type TYPE[,TYPE...]
+            Only match windows of one of these types: normal, dialog,
+            utility, toolbar, menu, splash, dock, desktop, notification,
+            critical_notification, osd, popup_menu, dropdown_menu, tooltip,
+            combobox, dnd, applet_popup.
+        --screen, --output SCREEN
+            Only match windows on a certain screen. SCREEN is an output name,
+            e.g. DP-1, or a screen number as output by
+            getwindowgeometry --shell.
+        --onlyvisible
+            Only match windows that are visible: not minimized or hidden, and
+            on the current desktop and activity.
+        --minimized, --not-minimized
+            Only match windows that are (or are not) minimized.
+        --fullscreen
+            Only match fullscreen windows.
+        --above
+            Only match windows that are kept above others.
+        --normal-only
+            Only match normal application windows. Excludes docks and panels,
+            desktops, notifications, dialogs, etc.
+        --where EXPRESSION
+            Only match windows for which EXPRESSION is true, e.g.
+            'class ~ "firefox" && !(title ~ "Private")'. Compare the fields of
+            --format with ~ and !~ (regular expression), == and != (literal
+            string, number or true/false), or <, <=, >, >= (number). A field
+            alone is tested for truth, e.g. 'minimized'. Combine conditions
+            with &&, || and !, and group them with parentheses.
+        --sort KEY [--reverse]
+            Sort the matching windows by KEY: stacking (topmost first),
+            focus-history (most recently active first), x, y, area, title,
+            class or pid. --reverse reverses the order. With --limit, the first
+            NUMBER windows in this order are kept.
         -l, --limit NUMBER
             Stop searching after finding NUMBER matching windows. The default
             is no search limit (which is equivalent to '--limit 0')
@@ -59,6 +120,13 @@ Window Query Commands:
         --any
             Match windows that match any condition (logically, 'or'). This is
             on by default.
+        --sync
+            If no windows match, wait until a matching window appears (or an
+            existing one starts matching, e.g. after a title change), then
+            continue the command chain.
+        --timeout SECONDS
+            With --sync, how long to wait for a matching window. The default
+            is the global --timeout.
 
     getactivewindow
         Select the currently active window.
@@ -70,6 +138,33 @@ Window Query Commands:
         --shell
             output shell data you can eval.
 
+Window Stack Commands:
+    savewindowstack NAME
+        Save the window stack under NAME.
+
+    loadwindowstack NAME
+        Replace the window stack with the one saved under NAME.
+
+    stackfilter [OPTIONS] [PATTERN]
+        Keep the windows in the stack that match, like search. Takes the
+        same options as search, except --sync. PATTERN is optional, e.g.
+          kdotool search konsole stackfilter --where '!above' getwindowname
+
+    stackunion NAME
+    stackintersect NAME
+    stackdiff NAME
+        Combine the window stack with the one saved under NAME: add its
+        windows, keep only windows that are also in it, or remove its
+        windows.
+
+    stackslice START [END]
+        Keep the windows from position START to END (inclusive, default: the
+        last one). Positions start at 1, negative positions count from the
+        end, like %N.
+
+    stackcount
+        Output the number of windows in the stack.
+
 Window Action Commands:
 
     General Syntax:
@@ -84,45 +179,60 @@ Window Action Commands:
     If not specified, it defaults to %1. I.e. the first result from the
     previous window query.
 
-    getwindowname [WINDOW]
+    getwindowname [--shell] [WINDOW]
         Output the name of a window. This is the same string that is displayed
         in the window titlebar.
 
     getwindowclassname [WINDOW]
         Output the class name of a window.
 
-    getwindowgeometry [WINDOW]
+    getwindowgeometry [--shell] [WINDOW]
         Output the geometry (location and position) of a window. The values
         include: x, y, width, height.
 
     getwindowid [WINDOW]
         Output the ID of a window.
 
-    getwindowpid [WINDOW]
+    getwindowpid [--shell] [WINDOW]
         Output the PID owning a window. This requires effort from the
         application owning a window and may not work for all windows.
 
-    windowactivate [WINDOW]
+    The --shell option of the above commands, get_desktop_for_window and
+    getmouselocation outputs shell data you can eval, e.g. WINDOW=, X=, Y=,
+    WIDTH=, HEIGHT=, SCREEN= for getwindowgeometry. Values are quoted as
+    needed.
+
+    windowactivate [--sync] [WINDOW]
         Activate a window. If the window is on another desktop, we will switch
         to that desktop.
+
+        --sync
+            After activating, wait until the window is actually active.
     
     windowraise [WINDOW] (KDE 6 only)
         Raise a window to the top of the window stack.
 
-    windowminimize [WINDOW]
+    windowminimize [--sync] [WINDOW]
         Minimize a window.
 
+        --sync
+            After minimizing, wait until the window is actually minimized.
+
     windowclose [WINDOW]
         Close a window.
 
-    windowsize [WINDOW] WIDTH HEIGHT
+    windowsize [--sync] [WINDOW] WIDTH HEIGHT
         Resize a window. Percentages are valid for WIDTH and HEIGHT. They are
         relative to the geometry of the screen the window is on.
 
         If the given WIDTH is literally 'x', then the window's current width
         will be unchanged. The same applies for 'y' for HEIGHT.
 
-    windowmove [--relative] [WINDOW] X Y
+        --sync
+            After resizing, wait until the window is actually resized. If no
+            resize is necessary, we will not wait.
+
+    windowmove [--relative] [--sync] [WINDOW] X Y
         Move a window. Percentages are valid for X and Y. They are relative to
         the geometry of the screen the window is on.
 
@@ -131,6 +241,14 @@ Window Action Commands:
 
         --relative
             Make movement relative to the current window position.
+
+        --sync
+            After moving, wait until the window is actually moved. If no
+            movement is necessary, we will not wait.
+
+    With --sync, the rest of the command chain runs once the change is done.
+    If it isn't done within the timeout (see --timeout), it is an error and
+    the rest of the chain is skipped.
     
     windowstate [--add PROPERTY] [--remove PROPERTY] [--toggle PROPERTY] [WINDOW]
         Change a property on a window.
@@ -154,23 +272,136 @@ Window Action Commands:
         single command. For example, you can do:
           kdotool windowstate --add above --remove below --toggle skip_taskbar
 
-    get_desktop_for_window [WINDOW]
-        Output the desktop number that a window is on.
+    get_desktop_for_window [--shell] [--all] [WINDOW]
+        Output the desktop that a window is on, see --desktop-format.
+
+        --all
+            Output all desktops the window is on, one per line. With
+            --shell, as DESKTOPS, separated by spaces.
 
-    set_desktop_for_window [WINDOW] NUMBER
+    set_desktop_for_window [WINDOW] DESKTOP
+    set_desktop_for_window [WINDOW] --relative N [--wrap]
+    set_desktop_for_window [WINDOW] --direction DIRECTION [--wrap]
         Move a window to a different desktop.
-        Specify the desktop number or "current_desktop" or "all".
+        Specify the DESKTOP or "current_desktop" or "all", or a desktop
+        relative to the window's, like set_desktop.
+
+    add_desktop_for_window [WINDOW] DESKTOP
+        Also show a window on DESKTOP, which can be "current_desktop".
+
+    remove_desktop_for_window [WINDOW] DESKTOP
+        Stop showing a window on DESKTOP, which can be "current_desktop". A
+        window must stay on at least one desktop.
+
+    get_activities_for_window [WINDOW]
+        Output the ids of the activities that a window is on.
+
+    set_activities_for_window [WINDOW] ACTIVITY[,ACTIVITY...]
+        Put a window on these activities, given by id or name. "current" is
+        the current activity, and "all" puts the window on all activities.
+
+Control Commands:
+    if-empty COMMAND... [else COMMAND...] end
+        Run the commands if the window stack is empty, or else the commands
+        after 'else'.
+
+    foreach COMMAND... end
+        Run the commands once for each window in the stack, with a window
+        stack of just that window. Afterwards, the window stack is restored.
+
+        Example: print the title and pid of every Konsole window
+          kdotool search konsole foreach getwindowname getwindowpid end
+
+    exit-if-empty
+        Stop the command chain if the window stack is empty. In behave, stop
+        handling the current event.
+
+    Blocks can be nested. Commands with --sync can't be used inside them.
+
+Event Commands:
+    behave [WINDOW] EVENT[,EVENT...] COMMAND [ARGS]...
+        Run the rest of the command chain every time one of the events
+        happens. The window stack of the chain contains the event's window.
+        kdotool keeps running and prints the output until interrupted with
+        Ctrl-C, unless --name is given.
+
+        If WINDOW is given, only events of these windows are handled.
+        Otherwise events of all windows are.
+
+        EVENT can be any of:
+
+        added       - a window was opened
+        removed     - a window was closed
+        activated   - a window was activated
+        caption     - the title of a window changed
+        geometry    - a window was moved or resized
+
+        Example: print the title of every new window
+          kdotool behave added getwindowname
+
+    watch [--events EVENT[,EVENT...]]
+        Print a JSON object for every window event until interrupted with
+        Ctrl-C. The objects contain the event name, the time and the
+        window's properties.
+
+        EVENT can be any of the events of behave, or:
+
+        desktop     - the current desktop changed, or a window was moved to
+                      another desktop
+
+        The default is all events.
 
 Global Commands:
     get_desktop
-        Output the current desktop number.
+        Output the current desktop, see --desktop-format.
     
-    set_desktop <number>
-        Change the current desktop to <number>.
+    set_desktop DESKTOP
+    set_desktop --relative N [--wrap]
+    set_desktop --direction DIRECTION [--wrap]
+        Change the current desktop to DESKTOP.
+
+        --relative N
+            Move N desktops forward, or backward if N is negative.
+        --direction left|right|up|down
+            Move to the neighboring desktop in the desktop grid.
+        --wrap
+            Wrap around at the first and last desktop, or at the edges of
+            the grid. Otherwise, stop there.
 
     get_num_desktops
         Output the current number of desktops.
 
+    set_num_desktops <number>
+        Add or remove desktops at the end until there are <number> desktops.
+
+    list_desktops
+        Output one line per desktop: its number, * for the current desktop
+        (- for the others), its id and its name, separated by tabs. With
+        --json, also its row in the desktop grid.
+
+    desktop_create NAME [POSITION]
+        Create a desktop named NAME. It gets the number POSITION; the
+        default is after the last desktop.
+
+    desktop_remove DESKTOP
+        Remove a desktop.
+
+    desktop_rename DESKTOP NAME
+        Rename a desktop.
+
+    DESKTOP can be given by its number, its id, or its name. Ids and names
+    don't change when desktops are reordered.
+
+    get_activity
+        Output the id of the current activity. With --json, also its name.
+
+    set_activity ACTIVITY
+        Switch to an activity, given by its id or name.
+
+    list_activities
+        Output the id and name of each activity. With --json, also whether it
+        is the current one.
+
     kwinscript --file <path> | --inline <code>
         Run arbitrary KWin JavaScript code directly.
         Useful for testing snippets or running scripts that don't fit
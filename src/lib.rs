@@ -1,18 +1,23 @@
-use std::error::Error;
-use std::io::Write;
-use std::sync::mpsc;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-
-use anyhow::{Context, anyhow};
-use dbus::{
-    blocking::{Connection, SyncConnection},
-    channel::MatchingReceiver,
-    message::MatchRule,
-};
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
+pub mod engine;
+use engine::run_step;
+
+mod error;
+pub use error::{Error, Result};
+
+mod expr;
+
+mod parser;
+
+mod query;
+pub use query::WindowQuery;
+
 mod templates;
-use templates::{SCRIPT_FOOTER, SCRIPT_HEADER};
+
+mod window;
+pub use window::{Desktop, Window, WindowState};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ActiveWindowInfo {
@@ -26,14 +31,31 @@ pub struct ActiveWindowInfo {
     pub height: f64,
 }
 
-#[derive(Default, Serialize)]
-struct Globals {
-    dbus_addr: String,
-    cmdline: String,
-    debug: bool,
-    marker: String,
-    script_name: String,
-    shortcut: String,
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: String,
+    pub caption: String,
+    pub resource_class: String,
+    pub resource_name: String,
+    pub window_role: String,
+    pub pid: u32,
+    pub geometry: Rect,
+    /// X11 desktop numbers of the desktops the window is on.
+    pub desktops: Vec<u32>,
+    pub on_all_desktops: bool,
+    pub minimized: bool,
+    pub fullscreen: bool,
+    pub keep_above: bool,
+    /// Name of the output (screen) the window is on, e.g. `DP-1`.
+    pub output: String,
 }
 
 const STEP_ACTIVE_WINDOW_INFO: &str = r#"
@@ -55,155 +77,53 @@ const STEP_ACTIVE_WINDOW_INFO: &str = r#"
     }
 "#;
 
-pub fn get_active_window_info() -> Result<ActiveWindowInfo, Box<dyn Error + Send + Sync>> {
-    get_active_window_info_impl().map_err(|err| err.into())
+const STEP_LIST_WINDOWS: &str = r#"
+    output_debug("STEP listwindows")
+    output_result(JSON.stringify(workspace_windowList().map(window_info)));
+"#;
+
+pub fn get_active_window_info() -> Result<ActiveWindowInfo> {
+    get_active_window_info_impl().map_err(Error::from)
 }
 
 fn get_active_window_info_impl() -> anyhow::Result<ActiveWindowInfo> {
-    let mut context = Globals {
-        cmdline: "kdotool::get_active_window_info".to_string(),
-        ..Default::default()
-    };
-
-    if std::env::var("KDE_SESSION_VERSION") != Ok("6".to_string()) {
-        return Err(anyhow!(
-            "Unsupported KDE version. kdotool only supports KDE Plasma 6."
-        ));
-    }
-
-    let unique_suffix = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .context("failed to read system time")?
-        .as_millis();
-    context.marker = format!("kdotool-lib-{unique_suffix}");
-    context.script_name = context.marker.clone();
-
-    // Establish the DBus listener connection first so we know the address
-    // to embed in the generated KWin script.
-    let self_conn = SyncConnection::new_session()?;
-    context.dbus_addr = self_conn.unique_name().to_string();
-
-    let script_contents = generate_script(&context)?;
-    let result_payload = run_script(&script_contents, &context, self_conn)?;
+    let result_payload = run_step(
+        "kdotool::get_active_window_info",
+        STEP_ACTIVE_WINDOW_INFO,
+        &serde_json::Value::Null,
+    )?;
     parse_active_window_info(&result_payload)
 }
 
-pub(crate) fn generate_script(globals: &Globals) -> anyhow::Result<String> {
-    let mut full_script = String::new();
-    let mut reg = handlebars::Handlebars::new();
-    reg.set_strict_mode(true);
-    let render_context = handlebars::Context::wraps(globals)?;
-
-    full_script.push_str(&reg.render_template_with_context(SCRIPT_HEADER, &render_context)?);
-    full_script
-        .push_str(&reg.render_template_with_context(STEP_ACTIVE_WINDOW_INFO, &render_context)?);
-    full_script.push_str(&reg.render_template_with_context(SCRIPT_FOOTER, &render_context)?);
-
-    Ok(full_script)
+/// Lists all windows managed by KWin, in the order of `workspace.windowList()`.
+pub fn list_windows() -> Result<Vec<WindowInfo>> {
+    list_windows_impl().map_err(Error::from)
 }
 
-pub(crate) fn run_script(
-    script_contents: &str,
-    context: &Globals,
-    self_conn: SyncConnection,
-) -> anyhow::Result<String> {
-    enum ScriptMessage {
-        Result(String),
-        Error(String),
-    }
-
-    let kwin_conn = Connection::new_session()?;
-    let kwin_proxy =
-        kwin_conn.with_proxy("org.kde.KWin", "/Scripting", Duration::from_millis(5000));
-
-    let (tx, rx) = mpsc::channel();
-
-    let _receiver = self_conn.start_receive(
-        MatchRule::new_method_call(),
-        Box::new(move |message, _connection| -> bool {
-            if let Some(member) = message.member()
-                && let Some(arg) = message.get1::<String>()
-            {
-                match member.as_ref() {
-                    "result" => {
-                        let _ = tx.send(ScriptMessage::Result(arg));
-                    }
-                    "error" => {
-                        let _ = tx.send(ScriptMessage::Error(arg));
-                    }
-                    _ => {}
-                }
-            }
-            true
-        }),
-    );
-
-    let mut script_file = tempfile::NamedTempFile::with_prefix("kdotool-")?;
-    script_file.write_all(script_contents.as_bytes())?;
-    let script_file_path = script_file.into_temp_path();
-
-    let script_id: i32;
-    (script_id,) = kwin_proxy.method_call(
-        "org.kde.kwin.Scripting",
-        "loadScript",
-        (script_file_path.to_str().unwrap(), &context.script_name),
+fn list_windows_impl() -> anyhow::Result<Vec<WindowInfo>> {
+    let result_payload = run_step(
+        "kdotool::list_windows",
+        STEP_LIST_WINDOWS,
+        &serde_json::Value::Null,
     )?;
-    if script_id < 0 {
-        return Err(anyhow!(
-            "Failed to load script. A script with the same name may already exist."
-        ));
-    }
-
-    let script_proxy = kwin_conn.with_proxy(
-        "org.kde.KWin",
-        format!("/Scripting/Script{script_id}"),
-        Duration::from_millis(5000),
-    );
-
-    let _: () = script_proxy.method_call("org.kde.kwin.Script", "run", ())?;
-    let _: () = script_proxy.method_call("org.kde.kwin.Script", "stop", ())?;
-
-    let start = Instant::now();
-    let timeout = Duration::from_secs(5);
-
-    let result = loop {
-        self_conn.process(Duration::from_millis(100))?;
-        match rx.try_recv() {
-            Ok(ScriptMessage::Result(payload)) => break Ok(payload),
-            Ok(ScriptMessage::Error(message)) => {
-                break Err(anyhow!("KWin script error: {message}"));
-            }
-            Err(mpsc::TryRecvError::Empty) => {
-                if start.elapsed() > timeout {
-                    break Err(anyhow!("Timed out waiting for KWin response"));
-                }
-            }
-            Err(mpsc::TryRecvError::Disconnected) => {
-                break Err(anyhow!("KWin response channel disconnected"));
-            }
-        }
-    };
-
-    let _: Result<(), _> = kwin_proxy.method_call(
-        "org.kde.kwin.Scripting",
-        "unloadScript",
-        (&context.script_name,),
-    );
-
-    result
+    parse_json_payload(&result_payload).context("failed to parse window list")
 }
 
 pub(crate) fn parse_active_window_info(payload: &str) -> anyhow::Result<ActiveWindowInfo> {
+    parse_json_payload(payload).context("failed to parse active window info")
+}
+
+pub(crate) fn parse_json_payload<T: serde::de::DeserializeOwned>(
+    payload: &str,
+) -> anyhow::Result<T> {
     // KWin sends JSON.stringify output as a DBus string, which arrives with
     // escaped inner quotes. Try parsing directly first; if that fails, try
     // interpreting as a JSON string literal to unescape it.
-    serde_json::from_str(payload)
-        .or_else(|_| {
-            let unescaped: String =
-                serde_json::from_str(payload).context("failed to unescape payload")?;
-            serde_json::from_str(&unescaped).context("failed to parse unescaped payload")
-        })
-        .context("failed to parse active window info")
+    serde_json::from_str(payload).or_else(|_| {
+        let unescaped: String =
+            serde_json::from_str(payload).context("failed to unescape payload")?;
+        serde_json::from_str(&unescaped).context("failed to parse unescaped payload")
+    })
 }
 
 #[cfg(test)]
@@ -224,4 +144,28 @@ mod tests {
         assert!((info.width - 800.0).abs() < f64::EPSILON);
         assert!((info.height - 600.0).abs() < f64::EPSILON);
     }
+
+    #[test]
+    fn parses_window_list() {
+        let payload = r#"[{"id":"{0b1c}","caption":"Terminal","resource_class":"org.kde.konsole","resource_name":"konsole","window_role":"","pid":4242,"geometry":{"x":10,"y":20,"width":800,"height":600},"desktops":[1,2],"on_all_desktops":false,"minimized":false,"fullscreen":true,"keep_above":false,"output":"DP-1"}]"#;
+        let windows: Vec<WindowInfo> = parse_json_payload(payload).expect("should parse payload");
+
+        assert_eq!(windows.len(), 1);
+        let w = &windows[0];
+        assert_eq!(w.id, "{0b1c}");
+        assert_eq!(w.resource_class, "org.kde.konsole");
+        assert_eq!(w.resource_name, "konsole");
+        assert_eq!(
+            w.geometry,
+            Rect {
+                x: 10.0,
+                y: 20.0,
+                width: 800.0,
+                height: 600.0
+            }
+        );
+        assert_eq!(w.desktops, vec![1, 2]);
+        assert!(w.fullscreen);
+        assert_eq!(w.output, "DP-1");
+    }
 }
@@ -0,0 +1,321 @@
+use anyhow::Context;
+use serde_json::json;
+
+use crate::engine::{
+    SearchOptions, new_registry, regex_escape, render_search, run_step, sort_key,
+    window_type_property,
+};
+use crate::{Error, Result, WindowInfo, parse_json_payload};
+
+const STEP_QUERY: &str = r#"
+    {{{search}}}
+    output_result(JSON.stringify(window_stack.map(window_info)));
+"#;
+
+/// A window search, equivalent to the `search` command.
+///
+/// Each field condition has its own pattern. Like `search`, a window matches
+/// if any condition matches, unless [`all`](Self::all) is set. A query without
+/// any condition matches every window.
+///
+/// ```no_run
+/// use kdotool::WindowQuery;
+///
+/// let windows = WindowQuery::new()
+///     .class("firefox")
+///     .title_regex(r"^GitHub\b")
+///     .desktop(2)
+///     .limit(1)
+///     .all()
+///     .run()?;
+/// # Ok::<(), kdotool::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WindowQuery {
+    opt: SearchOptions,
+}
+
+impl WindowQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Matches windows whose class contains `class`.
+    pub fn class(self, class: &str) -> Self {
+        self.class_regex(&regex_escape(class))
+    }
+
+    /// Matches windows whose class matches the regular expression `pattern`.
+    pub fn class_regex(mut self, pattern: &str) -> Self {
+        self.opt.match_class = true;
+        self.opt.class_pattern = pattern.to_string();
+        self
+    }
+
+    /// Matches windows whose class name (resource name) contains `classname`.
+    pub fn classname(self, classname: &str) -> Self {
+        self.classname_regex(&regex_escape(classname))
+    }
+
+    pub fn classname_regex(mut self, pattern: &str) -> Self {
+        self.opt.match_classname = true;
+        self.opt.classname_pattern = pattern.to_string();
+        self
+    }
+
+    /// Matches windows whose role contains `role`.
+    pub fn role(self, role: &str) -> Self {
+        self.role_regex(&regex_escape(role))
+    }
+
+    pub fn role_regex(mut self, pattern: &str) -> Self {
+        self.opt.match_role = true;
+        self.opt.role_pattern = pattern.to_string();
+        self
+    }
+
+    /// Matches windows whose title contains `title`.
+    pub fn title(self, title: &str) -> Self {
+        self.title_regex(&regex_escape(title))
+    }
+
+    pub fn title_regex(mut self, pattern: &str) -> Self {
+        self.opt.match_name = true;
+        self.opt.name_pattern = pattern.to_string();
+        self
+    }
+
+    /// Matches windows whose desktop file name contains `desktop_file`.
+    pub fn desktop_file(self, desktop_file: &str) -> Self {
+        self.desktop_file_regex(&regex_escape(desktop_file))
+    }
+
+    pub fn desktop_file_regex(mut self, pattern: &str) -> Self {
+        self.opt.match_desktopfile = true;
+        self.opt.desktopfile_pattern = pattern.to_string();
+        self
+    }
+
+    /// Matches the window with the given KWin internal id.
+    pub fn id(mut self, id: &str) -> Self {
+        self.opt.match_id = true;
+        self.opt.id_pattern = format!("^{}$", regex_escape(id));
+        self
+    }
+
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.opt.match_pid = true;
+        self.opt.pid = pid as i32;
+        self
+    }
+
+    /// Only returns windows on the desktop with the given X11 desktop number.
+    pub fn desktop(mut self, desktop: i32) -> Self {
+        self.opt.match_desktop = true;
+        self.opt.desktop = desktop.to_string();
+        self
+    }
+
+    /// Only returns windows on the desktop with the given id, or else name.
+    /// Unlike the number, these don't change when desktops are reordered.
+    pub fn desktop_name(mut self, desktop: &str) -> Self {
+        self.opt.match_desktop = true;
+        self.opt.desktop = desktop.to_string();
+        self
+    }
+
+    /// Only returns windows on the activity with the given id, or on the
+    /// current activity if `activity` is "current".
+    pub fn activity(mut self, activity: &str) -> Self {
+        self.opt.match_activity = true;
+        self.opt.activity = activity.to_string();
+        self
+    }
+
+    /// Only returns windows of the given type, e.g. "normal" or "dialog", as
+    /// accepted by `search --type`. Can be given several times to match any
+    /// of the types.
+    pub fn window_type(mut self, window_type: &str) -> Result<Self> {
+        let property = window_type_property(window_type).map_err(Error::from)?;
+        self.opt.window_types.push(property.to_string());
+        Ok(self)
+    }
+
+    /// Only returns windows on the given output, by name (e.g. `DP-1`) or by
+    /// index.
+    pub fn screen(mut self, screen: &str) -> Self {
+        self.opt.match_screen = true;
+        self.opt.screen = screen.to_string();
+        self
+    }
+
+    /// Only returns windows that are not minimized or hidden, and are on the
+    /// current desktop and activity.
+    pub fn only_visible(mut self) -> Self {
+        self.opt.only_visible = true;
+        self
+    }
+
+    /// Only returns windows that are (or are not) minimized.
+    pub fn minimized(mut self, minimized: bool) -> Self {
+        self.opt.match_minimized = true;
+        self.opt.minimized = minimized;
+        self
+    }
+
+    pub fn fullscreen(mut self) -> Self {
+        self.opt.fullscreen = true;
+        self
+    }
+
+    /// Only returns windows that are kept above others.
+    pub fn above(mut self) -> Self {
+        self.opt.above = true;
+        self
+    }
+
+    /// Only returns normal application windows, i.e. no docks, desktops,
+    /// notifications, dialogs, ...
+    pub fn normal_only(mut self) -> Self {
+        self.opt.normal_only = true;
+        self
+    }
+
+    /// Only returns windows matching a `search --where` expression, e.g.
+    /// `class ~ "firefox" && !(title ~ "Private")`. Fails if the expression
+    /// is invalid.
+    pub fn filter(mut self, expr: &str) -> Result<Self> {
+        self.opt.where_expr = crate::expr::compile_where(expr).map_err(Error::from)?;
+        Ok(self)
+    }
+
+    /// Sorts the matches by `key`, as accepted by `search --sort`, e.g.
+    /// "stacking" for the topmost window first.
+    pub fn sort(mut self, key: &str) -> Result<Self> {
+        self.opt.sort = sort_key(key).map_err(Error::from)?.to_string();
+        Ok(self)
+    }
+
+    /// Reverses the order given by [`sort`](Self::sort).
+    pub fn reverse(mut self) -> Self {
+        self.opt.reverse = true;
+        self
+    }
+
+    /// Stops after `limit` matches. 0 means no limit.
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.opt.limit = limit;
+        self
+    }
+
+    /// Requires all conditions to match.
+    pub fn all(mut self) -> Self {
+        self.opt.match_all = true;
+        self
+    }
+
+    /// Requires any condition to match. This is the default.
+    pub fn any(mut self) -> Self {
+        self.opt.match_all = false;
+        self
+    }
+
+    pub fn case_sensitive(mut self) -> Self {
+        self.opt.match_case = true;
+        self
+    }
+
+    /// Generates the JavaScript of the `search` step for this query.
+    pub(crate) fn to_script(&self) -> anyhow::Result<String> {
+        let mut opt = self.opt.clone();
+        if !(opt.match_class
+            || opt.match_classname
+            || opt.match_role
+            || opt.match_name
+            || opt.match_pid
+            || opt.match_id
+            || opt.match_desktopfile)
+        {
+            opt.match_all = true;
+        }
+        render_search(&new_registry(), &opt)
+    }
+
+    /// Runs the query and returns the matching windows.
+    pub fn run(&self) -> Result<Vec<WindowInfo>> {
+        self.run_impl().map_err(Error::from)
+    }
+
+    fn run_impl(&self) -> anyhow::Result<Vec<WindowInfo>> {
+        let payload = run_step(
+            "kdotool::WindowQuery::run",
+            STEP_QUERY,
+            &json!({ "search": self.to_script()? }),
+        )?;
+        parse_json_payload(&payload).context("failed to parse window list")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_patterns_are_escaped() {
+        let script = WindowQuery::new()
+            .class("org.kde.konsole")
+            .title_regex("^a.b$")
+            .to_script()
+            .unwrap();
+        assert!(script.contains(r#"new RegExp("org\\.kde\\.konsole", re_opts)"#));
+        assert!(script.contains(r#"new RegExp("^a.b$", re_opts)"#));
+        assert!(!script.contains("re_role"));
+    }
+
+    #[test]
+    fn state_filters_are_applied() {
+        let script = WindowQuery::new()
+            .minimized(false)
+            .normal_only()
+            .to_script()
+            .unwrap();
+        assert!(script.contains("if (w.minimized != false) continue;"));
+        assert!(script.contains("if (!w.normalWindow) continue;"));
+        assert!(!script.contains("window_visible(w)"));
+    }
+
+    #[test]
+    fn filter_is_compiled() {
+        let script = WindowQuery::new()
+            .filter("pid == 42 && !above")
+            .unwrap()
+            .to_script()
+            .unwrap();
+        assert!(
+            script.contains("if (!((Number(w.pid) == 42) && !Boolean(w.keepAbove))) continue;")
+        );
+        assert!(WindowQuery::new().filter("pid ==").is_err());
+    }
+
+    #[test]
+    fn sort_applies_limit_afterwards() {
+        let script = WindowQuery::new()
+            .sort("Stacking")
+            .unwrap()
+            .reverse()
+            .limit(1)
+            .to_script()
+            .unwrap();
+        assert!(script.contains(r#"windows_sort(window_stack, "stacking", true);"#));
+        assert!(script.contains("window_stack.splice(1);"));
+        assert!(!script.contains("break;"));
+        assert!(WindowQuery::new().sort("age").is_err());
+    }
+
+    #[test]
+    fn empty_query_matches_all_windows() {
+        let script = WindowQuery::new().limit(3).to_script().unwrap();
+        assert!(script.contains("if (true"));
+        assert!(script.contains("3 > 0"));
+    }
+}
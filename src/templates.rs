@@ -2,19 +2,25 @@
 
 pub const SCRIPT_HEADER: &str = r#"
 {{#if debug}}
-print("{{{marker}}} START");
+print({{{js marker}}}, "START");
 {{/if}}
 
+// Number of messages sent so far, reported by output_done() so that the
+// receiver knows when it has got all of them.
+var message_count = 0;
+
 function output_debug(message) {
     {{#if debug}}
-    print("{{{marker}}} DEBUG", message);
-    callDBus("{{{dbus_addr}}}", "/", "", "debug", message.toString());
+    print({{{js marker}}}, "DEBUG", message);
+    callDBus({{{js dbus_addr}}}, "/", "", "debug", message.toString());
+    message_count++;
     {{/if}}
 }
 
 function output_error(message) {
-    print("{{{marker}}} ERROR", message);
-    callDBus("{{{dbus_addr}}}", "/", "", "error", message.toString());
+    print({{{js marker}}}, "ERROR", message);
+    callDBus({{{js dbus_addr}}}, "/", "", "error", message.toString());
+    message_count++;
 }
 
 function output_result(message) {
@@ -22,9 +28,134 @@ function output_result(message) {
         message = "null";
     }
     {{#if debug}}
-    print("{{{marker}}} RESULT", message);
+    print({{{js marker}}}, "RESULT", message);
     {{/if}}
-    callDBus("{{{dbus_addr}}}", "/", "", "result", message.toString());
+    callDBus({{{js dbus_addr}}}, "/", "", "result", message.toString());
+    message_count++;
+}
+
+// Outputs the result of a query, either as JSON or as lines of text.
+function output_query(value, text) {
+    {{#if json}}
+    output_result(JSON.stringify(value));
+    {{else}}
+    for (const line of [].concat(text)) {
+        output_result(line);
+    }
+    {{/if}}
+}
+
+// Quotes a value for a POSIX shell, unless it is safe as is.
+function shell_quote(value) {
+    const s = String(value);
+    return /^[A-Za-z0-9_.,:\/+-]+$/.test(s) ? s : "'" + s.replace(/'/g, "'\\''") + "'";
+}
+
+// Outputs NAME=value lines that can be eval'ed by a shell.
+function output_shell(vars) {
+    for (const [name, value] of Object.entries(vars)) {
+        output_result(`${name}=${shell_quote(value)}`);
+    }
+}
+
+// Tells the receiver to wait another `ms` milliseconds for the script.
+function output_wait(ms) {
+    {{#unless shortcut}}
+    callDBus({{{js dbus_addr}}}, "/", "", "wait", ms.toString());
+    {{/unless}}
+}
+
+// Waits until the conditions that steps with --sync pushed to `pending`
+// hold, re-checking them whenever one of their signals fires, then continues
+// the chain with next(). Gives up the chain after `timeout_ms`.
+function sync_wait(pending, timeout_ms, next) {
+    const satisfied = () => pending.every((p) => p.check());
+    const run_next = () => {
+        try {
+            next();
+        } catch (e) {
+            output_error("Script error: " + e);
+            output_done();
+        }
+    };
+    if (satisfied()) {
+        run_next();
+        return;
+    }
+    output_wait(timeout_ms);
+    const timer = new QTimer();
+    const finish = () => {
+        timer.stop();
+        for (const p of pending) {
+            p.signal.disconnect(p.handler);
+        }
+    };
+    for (const p of pending) {
+        p.handler = () => {
+            if (satisfied()) {
+                finish();
+                run_next();
+            }
+        };
+        p.signal.connect(p.handler);
+    }
+    timer.singleShot = true;
+    timer.interval = timeout_ms;
+    timer.timeout.connect(() => {
+        finish();
+        output_error("Timed out waiting for --sync");
+        output_done();
+    });
+    timer.start();
+}
+
+// A signal that fires when a window is added, or when the caption, class,
+// desktop file or desktops of a window change, for waiting until a search
+// matches.
+function windows_changed_signal() {
+    const window_signals = (w) =>
+        [w.captionChanged, w.windowClassChanged, w.desktopFileNameChanged, w.desktopsChanged]
+            .filter((s) => s);
+    let handler = null;
+    const on_added = (w) => {
+        for (const s of window_signals(w)) {
+            s.connect(handler);
+        }
+        handler();
+    };
+    return {
+        connect: (f) => {
+            handler = f;
+            for (const w of workspace_windowList()) {
+                for (const s of window_signals(w)) {
+                    s.connect(f);
+                }
+            }
+            workspace.windowAdded.connect(on_added);
+        },
+        disconnect: (f) => {
+            workspace.windowAdded.disconnect(on_added);
+            for (const w of workspace_windowList()) {
+                for (const s of window_signals(w)) {
+                    s.disconnect(f);
+                }
+            }
+        },
+    };
+}
+
+// Tells the receiver that the script keeps running and reporting events,
+// instead of finishing with output_done().
+function output_attached() {
+    {{#unless shortcut}}
+    callDBus({{{js dbus_addr}}}, "/", "", "attached", message_count.toString());
+    {{/unless}}
+}
+
+function output_done() {
+    {{#unless shortcut}}
+    callDBus({{{js dbus_addr}}}, "/", "", "done", message_count.toString());
+    {{/unless}}
 }
 
 workspace_windowList                  = () => workspace.windowList();
@@ -32,108 +163,478 @@ workspace_activeWindow                = () => workspace.activeWindow;
 workspace_setActiveWindow             = (window) => { workspace.activeWindow = window; };
 workspace_raiseWindow                 = (window) => { workspace.raiseWindow(window); };
 workspace_currentDesktop              = () => workspace.currentDesktop.x11DesktopNumber;
-workspace_setCurrentDesktop           = (id) => {
-    let d = workspace.desktops.find((d) => d.x11DesktopNumber == id);
+workspace_setCurrentDesktop           = (desktop) => {
+    let d = workspace_findDesktop(desktop);
     if (d) {
         workspace.currentDesktop = d;
-    } else {
-        output_error(`Invalid desktop number ${id}`);
     }
 };
 workspace_numDesktops                 = () => workspace.desktops.length;
+workspace_setNumDesktops              = (n) => {
+    while (workspace.desktops.length < n) {
+        workspace.createDesktop(workspace.desktops.length, "");
+    }
+    while (workspace.desktops.length > n) {
+        workspace.removeDesktop(workspace.desktops[workspace.desktops.length - 1]);
+    }
+};
+// Finds a desktop by its number or id, or else by its name.
+workspace_findDesktop                 = (desktop) => {
+    let d;
+    if (typeof desktop == "object") {
+        // {id: ...} or {name: ...}, from the library.
+        d = workspace.desktops.find((d) => d.id === desktop.id || d.name === desktop.name);
+        desktop = desktop.id || desktop.name;
+    } else {
+        d = workspace.desktops.find((d) => d.id == desktop || d.x11DesktopNumber == desktop)
+            || workspace.desktops.find((d) => d.name == desktop);
+    }
+    if (!d) {
+        output_error(`Invalid desktop ${desktop}`);
+    }
+    return d;
+};
+// All desktops a window is on.
+window_desktops                       = (window) => window.onAllDesktops || window.desktops.length == 0
+    ? workspace.desktops : window.desktops;
+// Adds a desktop to the window's desktops. -1 is the current desktop.
+window_addDesktop                     = (window, desktop) => {
+    let d = desktop === -1 ? workspace.currentDesktop : workspace_findDesktop(desktop);
+    if (d && !window_desktops(window).some((x) => x.id == d.id)) {
+        window.desktops = window.desktops.concat([d]);
+    }
+};
+// Removes a desktop from the window's desktops. A window must stay on at
+// least one desktop.
+window_removeDesktop                  = (window, desktop) => {
+    let d = desktop === -1 ? workspace.currentDesktop : workspace_findDesktop(desktop);
+    if (!d) {
+        return;
+    }
+    const desktops = window_desktops(window).filter((x) => x.id != d.id);
+    if (desktops.length == 0) {
+        output_error(`Can't remove the only desktop of window ${window.internalId}`);
+    } else if (desktops.length < window_desktops(window).length) {
+        window.desktops = desktops;
+    }
+};
+// The desktop `delta` desktops after `from`. Without `wrap`, stops at the
+// first and last desktop.
+workspace_desktopRelative             = (from, delta, wrap) => {
+    const n = workspace.desktops.length;
+    let i = from.x11DesktopNumber - 1 + delta;
+    i = wrap ? ((i % n) + n) % n : Math.max(0, Math.min(n - 1, i));
+    return workspace.desktops.find((d) => d.x11DesktopNumber == i + 1);
+};
+// The neighbor of `from` in the desktop grid, which is filled row by row.
+// Without `wrap`, stays at `from` at the edges.
+workspace_desktopInDirection          = (from, direction, wrap) => {
+    const n = workspace.desktops.length;
+    const columns = workspace.desktopGridWidth;
+    const rows = workspace.desktopGridHeight;
+    const [dr, dc] = {left: [0, -1], right: [0, 1], up: [-1, 0], down: [1, 0]}[direction];
+    let row = Math.floor((from.x11DesktopNumber - 1) / columns);
+    let column = (from.x11DesktopNumber - 1) % columns;
+    for (let i = 0; i < Math.max(rows, columns); i++) {
+        row += dr;
+        column += dc;
+        if (wrap) {
+            row = (row + rows) % rows;
+            column = (column + columns) % columns;
+        } else if (row < 0 || row >= rows || column < 0 || column >= columns) {
+            break;
+        }
+        // The last row may not be full.
+        const number = row * columns + column + 1;
+        if (number <= n) {
+            return workspace.desktops.find((d) => d.x11DesktopNumber == number);
+        }
+    }
+    return from;
+};
+// Formats a desktop for output, as selected by --desktop-format.
+desktop_output                        = (d) => {
+    if (!d) {
+        return d;
+    }
+    switch ({{{js desktop_format}}}) {
+        case "name": return d.name;
+        case "id": return d.id;
+        default: return d.x11DesktopNumber;
+    }
+};
+desktop_info                          = (d) => ({
+    id: d.id,
+    number: d.x11DesktopNumber,
+    name: d.name,
+    row: Math.floor((d.x11DesktopNumber - 1) / workspace.desktopGridWidth) + 1,
+    current: d === workspace.currentDesktop,
+});
 window_x11DesktopIds                  = (window) => window.desktops.map((d) => d.x11DesktopNumber);
-window_setX11DesktopId                = (window, id) => {
-    if (id == -1) {
+window_info                           = (window) => ({
+    id: window.internalId.toString(),
+    caption: window.caption,
+    resource_class: window.resourceClass,
+    resource_name: window.resourceName,
+    window_role: window.windowRole,
+    pid: window.pid,
+    geometry: {
+        x: window.x,
+        y: window.y,
+        width: window.width,
+        height: window.height,
+    },
+    desktops: window_x11DesktopIds(window),
+    on_all_desktops: window.onAllDesktops,
+    minimized: window.minimized,
+    fullscreen: window.fullScreen,
+    keep_above: window.keepAbove,
+    output: window.output ? window.output.name : "",
+});
+// Loads the activities with their names, which KWin doesn't expose, from the
+// activity manager, then continues the chain with next(activity_list).
+function activities_load(next) {
+    const list = workspace.activities.map((id) => ({id: id, name: ""}));
+    let pending = list.length;
+    const run_next = () => {
+        try {
+            next(list);
+        } catch (e) {
+            output_error("Script error: " + e);
+            output_done();
+        }
+    };
+    if (pending == 0) {
+        run_next();
+        return;
+    }
+    output_wait({{{timeout_ms}}});
+    for (const a of list) {
+        callDBus("org.kde.ActivityManager", "/ActivityManager/Activities",
+                 "org.kde.ActivityManager.Activities", "ActivityName", a.id, (name) => {
+            a.name = name;
+            if (--pending == 0) {
+                run_next();
+            }
+        });
+    }
+}
+// Finds an activity of `list` by id, or else by name.
+activities_find                       = (list, activity) => {
+    let a = list.find((a) => a.id == activity) || list.find((a) => a.name == activity);
+    if (!a) {
+        output_error(`Invalid activity ${activity}`);
+    }
+    return a;
+};
+// Puts a window on activities, see `activities_find`. "current" is the current
+// activity, and "all" all activities.
+window_setActivities                  = (window, list, activities) => {
+    if (activities.includes("all")) {
+        window.activities = [];
+        return;
+    }
+    let ids = [];
+    for (const activity of activities) {
+        let a = activity == "current" ? {id: workspace.currentActivity} : activities_find(list, activity);
+        if (!a) {
+            return;
+        }
+        if (!ids.includes(a.id)) {
+            ids.push(a.id);
+        }
+    }
+    window.activities = ids;
+};
+window_onActivity                     = (window, activity) => window.activities.length == 0
+    || window.activities.includes(activity == "current" ? workspace.currentActivity : activity);
+window_onScreen                       = (window, screen) => window.output != null
+    && (window.output.name == screen || workspace.screens.indexOf(window.output).toString() == screen);
+window_visible                        = (window) => !window.minimized && !window.hidden
+    && (window.onAllDesktops || window_x11DesktopIds(window).includes(workspace_currentDesktop()))
+    && (window.activities.length == 0 || window.activities.includes(workspace.currentActivity));
+// Moves a window to a desktop, see `workspace_findDesktop`. -1 is the current
+// desktop, and -2 all desktops.
+window_setDesktop                     = (window, desktop) => {
+    if (desktop === -1) {
         window.desktops = [workspace.currentDesktop];
-    } else if (id == -2) {
+    } else if (desktop === -2) {
         window.onAllDesktops = true;
     } else {
-        let d = workspace.desktops.find((d) => d.x11DesktopNumber == id);
+        let d = workspace_findDesktop(desktop);
         if (d) {
             window.desktops = [d];
-        } else {
-            output_error(`Invalid desktop number ${id}`);
         }
     }
 };
+// Sort keys of `search --sort`. Topmost windows come first in stacking
+// order. KWin doesn't expose the focus history, so it is approximated by the
+// active window, followed by the others in stacking order, as windows are
+// raised when activated.
+window_sortKeys                       = {
+    "stacking": (window) => -workspace.stackingOrder.indexOf(window),
+    "focus-history": (window) => window === workspace.activeWindow
+        ? -workspace.stackingOrder.length : -workspace.stackingOrder.indexOf(window),
+    "x": (window) => window.x,
+    "y": (window) => window.y,
+    "area": (window) => window.width * window.height,
+    "title": (window) => window.caption,
+    "class": (window) => window.resourceClass,
+    "pid": (window) => window.pid,
+};
+windows_sort                          = (windows, key, reverse) => {
+    const keys = new Map(windows.map((w) => [w, window_sortKeys[key](w)]));
+    windows.sort((a, b) => {
+        const ka = keys.get(a), kb = keys.get(b);
+        const order = (typeof ka == "string") ? ka.localeCompare(kb) : ka - kb;
+        return reverse ? -order : order;
+    });
+};
 
-function run() {
+function run_chain() {
     var window_stack = [];
+    var window_stacks = {};
 "#;
 
 pub const SCRIPT_FOOTER: &str = r#"
 }
 
+function run() {
+    message_count = 0;
+    try {
+        run_chain();
+    } catch (e) {
+        output_error("Script error: " + e);
+        output_done();
+    }
+}
+
 {{#if shortcut}}
-registerShortcut("{{#if script_name}}{{{script_name}}}{{else}}{{{marker}}}{{/if}}", "{{#if script_name}}{{{script_name}}}{{else}}{{{cmdline}}}{{/if}}", "{{{shortcut}}}", run);
+registerShortcut({{#if script_name}}{{{js script_name}}}{{else}}{{{js marker}}}{{/if}}, {{#if script_name}}{{{js script_name}}}{{else}}{{{js cmdline}}}{{/if}}, {{{js shortcut}}}, run);
 {{else}}
 run();
 {{/if}}
 
 {{#if debug}}
-print("{{{marker}}} FINISH");
+print({{{js marker}}}, "FINISH");
 {{/if}}
 "#;
 
 pub const STEP_SEARCH: &str = r#"
-    output_debug("STEP search {{{search_term}}}")
-    const match_case = {{{match_case}}};
-    const re_opts = (match_case ? "" : "i");
-    const re = new RegExp(String.raw`{{{search_term}}}`, re_opts);
-    var t = workspace_windowList();
-    window_stack = [];
-    for (var i=0; i<t.length; i++) {
-        let w = t[i];
-        if ({{#if match_all}}true{{else}}false{{/if}}
-            {{#if match_class}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.resourceClass.search(re) >= 0
-            {{/if}}
-            {{#if match_classname}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.resourceName.search(re) >= 0
-            {{/if}}
-            {{#if match_role}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.windowRole.search(re) >= 0
-            {{/if}}
-            {{#if match_name}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.caption.search(re) >= 0
-            {{/if}}
-            {{#if match_pid}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.pid == {{{pid}}}
-            {{/if}}
-            {{#if match_id}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.internalId.toString().search(re) >= 0
-            {{/if}}
-        ) {
-            {{#if match_desktop}}
-            if (window_x11DesktopIds(w).indexOf({{{desktop}}}) < 0) continue;
-            {{/if}}
-            window_stack.push(w);
-            if ({{{limit}}} > 0 && window_stack.length >= {{{limit}}}) {
-                break;
+    output_debug("STEP search " + {{{js search_term}}})
+    {
+        const match_case = {{{match_case}}};
+        const re_opts = (match_case ? "" : "i");
+        {{#if match_class}}
+        const re_class = new RegExp({{{js class_pattern}}}, re_opts);
+        {{/if}}
+        {{#if match_classname}}
+        const re_classname = new RegExp({{{js classname_pattern}}}, re_opts);
+        {{/if}}
+        {{#if match_role}}
+        const re_role = new RegExp({{{js role_pattern}}}, re_opts);
+        {{/if}}
+        {{#if match_name}}
+        const re_name = new RegExp({{{js name_pattern}}}, re_opts);
+        {{/if}}
+        {{#if match_id}}
+        const re_id = new RegExp({{{js id_pattern}}}, re_opts);
+        {{/if}}
+        {{#if match_desktopfile}}
+        const re_desktopfile = new RegExp({{{js desktopfile_pattern}}}, re_opts);
+        {{/if}}
+        {{#if match_desktop}}
+        const search_desktop = workspace_findDesktop({{{js desktop}}});
+        {{/if}}
+        {{#if match_activity}}
+        const search_activity = {{#if activity_by_name}}(activities_find(activity_list, {{{js activity}}}) || {}).id{{else}}{{{js activity}}}{{/if}};
+        {{/if}}
+        const search = () => {
+            var t = {{#if from_stack}}window_stack.slice(){{else}}workspace_windowList(){{/if}};
+            window_stack = [];
+            for (var i=0; i<t.length; i++) {
+                let w = t[i];
+                if ({{#if match_all}}true{{else}}false{{/if}}
+                    {{#if match_class}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.resourceClass.search(re_class) >= 0
+                    {{/if}}
+                    {{#if match_classname}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.resourceName.search(re_classname) >= 0
+                    {{/if}}
+                    {{#if match_role}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.windowRole.search(re_role) >= 0
+                    {{/if}}
+                    {{#if match_name}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.caption.search(re_name) >= 0
+                    {{/if}}
+                    {{#if match_pid}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.pid == {{{pid}}}
+                    {{/if}}
+                    {{#if match_id}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.internalId.toString().search(re_id) >= 0
+                    {{/if}}
+                    {{#if match_desktopfile}}
+                    {{#if match_all}}&&{{else}}||{{/if}}
+                    w.desktopFileName.search(re_desktopfile) >= 0
+                    {{/if}}
+                ) {
+                    {{#if match_desktop}}
+                    if (!search_desktop || !w.desktops.some((d) => d.id == search_desktop.id)) continue;
+                    {{/if}}
+                    {{#if match_activity}}
+                    if (!search_activity || !window_onActivity(w, search_activity)) continue;
+                    {{/if}}
+                    {{#if window_types}}
+                    if (!{{{js window_types}}}.some((t) => w[t])) continue;
+                    {{/if}}
+                    {{#if match_screen}}
+                    if (!window_onScreen(w, {{{js screen}}})) continue;
+                    {{/if}}
+                    {{#if only_visible}}
+                    if (!window_visible(w)) continue;
+                    {{/if}}
+                    {{#if match_minimized}}
+                    if (w.minimized != {{{minimized}}}) continue;
+                    {{/if}}
+                    {{#if fullscreen}}
+                    if (!w.fullScreen) continue;
+                    {{/if}}
+                    {{#if above}}
+                    if (!w.keepAbove) continue;
+                    {{/if}}
+                    {{#if normal_only}}
+                    if (!w.normalWindow) continue;
+                    {{/if}}
+                    {{#if where_expr}}
+                    if (!({{{where_expr}}})) continue;
+                    {{/if}}
+                    window_stack.push(w);
+                    {{#unless sort}}
+                    if ({{{limit}}} > 0 && window_stack.length >= {{{limit}}}) {
+                        break;
+                    }
+                    {{/unless}}
+                }
             }
-        }
+            {{#if sort}}
+            windows_sort(window_stack, {{{js sort}}}, {{{reverse}}});
+            if ({{{limit}}} > 0) {
+                window_stack.splice({{{limit}}});
+            }
+            {{/if}}
+        };
+        search();
+        {{#if sync}}
+        sync_pending.push({
+            signal: windows_changed_signal(),
+            check: () => {
+                search();
+                return window_stack.length > 0;
+            },
+        });
+        {{/if}}
     }
 "#;
 
 pub const STEP_GETACTIVEWINDOW: &str = r#"
     output_debug("STEP getactivewindow")
-    var window_stack = [workspace_activeWindow()];
+    window_stack = [workspace_activeWindow()];
 "#;
 
 pub const STEP_SAVEWINDOWSTACK: &str = r#"
     output_debug("STEP savewindowstack")
-    var window_stack_{{{name}}} = window_stack;
+    window_stacks[{{{js name}}}] = window_stack;
 "#;
 
 pub const STEP_LOADWINDOWSTACK: &str = r#"
     output_debug("STEP loadwindowstack")
-    var window_stack = window_stack_{{{name}}};
+    if ({{{js name}}} in window_stacks) {
+        window_stack = window_stacks[{{{js name}}}];
+    } else {
+        output_error("Unknown window stack " + {{{js name}}});
+        window_stack = [];
+    }
+"#;
+
+// Combines the window stack with the saved stack `other`, see `STACK_OPS`.
+pub const STEP_STACK_OP: &str = r#"
+    output_debug("STEP {{{step_name}}} " + {{{js name}}})
+    if ({{{js name}}} in window_stacks) {
+        const other = window_stacks[{{{js name}}}];
+        {{{op}}}
+    } else {
+        output_error("Unknown window stack " + {{{js name}}});
+    }
+"#;
+
+pub const STACK_OPS: phf::Map<&'static str, &'static str> = phf::phf_map! {
+    "stackunion"     => "window_stack = window_stack.concat(other.filter((w) => !window_stack.includes(w)));",
+    "stackintersect" => "window_stack = window_stack.filter((w) => other.includes(w));",
+    "stackdiff"      => "window_stack = window_stack.filter((w) => !other.includes(w));",
+};
+
+// START and END are 1-based and inclusive, negative values count from the
+// end, like %N.
+pub const STEP_STACKSLICE: &str = r#"
+    output_debug("STEP stackslice {{{start}}} {{{end}}}")
+    {
+        const start = {{{start}}} > 0 ? {{{start}}} - 1 : window_stack.length + {{{start}}};
+        const end = {{{end}}} > 0 ? {{{end}}} : window_stack.length + {{{end}}} + 1;
+        window_stack = window_stack.slice(Math.max(start, 0), Math.max(end, 0));
+    }
+"#;
+
+pub const STEP_STACKCOUNT: &str = r#"
+    output_debug("STEP stackcount")
+    output_query({count: window_stack.length}, window_stack.length);
+"#;
+
+// Control blocks. They are closed by `end`, not by a closing of the step, as
+// the steps inside them must not wait, see `generate_chain`.
+pub const STEP_IF_EMPTY: &str = r#"
+    output_debug("STEP if-empty")
+    if (window_stack.length == 0) {
+"#;
+
+pub const STEP_ELSE: &str = r#"
+    } else {
+"#;
+
+pub const STEP_END_IF: &str = r#"
+    }
+"#;
+
+// Runs the block for each window, with a window stack of just that window.
+// The window stack is restored afterwards.
+pub const STEP_FOREACH: &str = r#"
+    output_debug("STEP foreach")
+    {
+    const foreach_stack = window_stack;
+    for (const foreach_window of foreach_stack) {
+        window_stack = [foreach_window];
+"#;
+
+pub const STEP_END_FOREACH: &str = r#"
+    }
+    window_stack = foreach_stack;
+    }
+"#;
+
+// Skips the rest of the chain. In a `behave` handler, only for this event.
+pub const STEP_EXIT_IF_EMPTY: &str = r#"
+    output_debug("STEP exit-if-empty")
+    if (window_stack.length == 0) {
+        {{#unless in_handler}}
+        output_done();
+        {{/unless}}
+        return;
+    }
 "#;
 
 pub const STEP_ACTION_ON_WINDOW_ID: &str = r#"
@@ -141,7 +642,7 @@ pub const STEP_ACTION_ON_WINDOW_ID: &str = r#"
     var t = workspace_windowList();
     for (var i=0; i<t.length; i++) {
         let w = t[i];
-        if (w.internalId == "{{{window_id}}}") {
+        if (w.internalId == {{{js window_id}}}) {
             {{{action}}}
             break;
         }
@@ -172,20 +673,243 @@ pub const STEP_ACTION_ON_STACK_ALL: &str = r#"
 
 pub const STEP_LAST_OUTPUT: &str = r#"
     for (var i = 0; i < window_stack.length; ++i) {
-        output_result(window_stack[i].internalId);
+        {{#if format_expr}}
+        const w = window_stack[i];
+        output_result({{{format_expr}}});
+        {{else}}
+        output_query(window_info(window_stack[i]), window_stack[i].internalId);
+        {{/if}}
+    }
+"#;
+
+// Fields available in `--format` and `search --where`, and the expressions
+// computing them from the window `w`.
+pub const WINDOW_FIELDS: phf::Map<&'static str, &'static str> = phf::phf_map! {
+    "id"         => "w.internalId",
+    "title"      => "w.caption",
+    "name"       => "w.caption",
+    "class"      => "w.resourceClass",
+    "classname"  => "w.resourceName",
+    "role"       => "w.windowRole",
+    "desktopfile" => "w.desktopFileName",
+    "pid"        => "w.pid",
+    "x"          => "w.x",
+    "y"          => "w.y",
+    "width"      => "w.width",
+    "height"     => "w.height",
+    "desktop"    => "window_x11DesktopIds(w)[0]",
+    "desktopname" => "(w.desktops.length ? w.desktops[0].name : \"\")",
+    "desktopid"  => "(w.desktops.length ? w.desktops[0].id : \"\")",
+    "screen"     => "workspace.screens.indexOf(w.output)",
+    "output"     => "(w.output ? w.output.name : \"\")",
+    "minimized"  => "w.minimized",
+    "fullscreen" => "w.fullScreen",
+    "above"      => "w.keepAbove",
+};
+
+pub const STEP_DONE: &str = r#"
+    output_done();
+"#;
+
+// Wraps a step with --sync. The rest of the chain runs in the callback of
+// sync_wait(), closed by STEP_SYNC_CLOSING.
+pub const STEP_SYNC_WAIT: &str = r#"
+    {
+    const sync_pending = [];
+    {{{step}}}
+    sync_wait(sync_pending, {{{timeout_ms}}}, () => {
+"#;
+
+pub const STEP_SYNC_CLOSING: &str = r#"
+    });
+    }
+"#;
+
+// Runs the chain in the callback of activities_load(), closed by
+// STEP_ACTIVITIES_CLOSING.
+pub const STEP_ACTIVITIES_LOAD: &str = r#"
+    activities_load((activity_list) => {
+"#;
+
+pub const STEP_ACTIVITIES_CLOSING: &str = r#"
+    });
+"#;
+
+pub const STEP_ATTACHED: &str = r#"
+    output_attached();
+"#;
+
+pub const BEHAVE_EVENTS: &[&str] = &["added", "removed", "activated", "caption", "geometry"];
+pub const WATCH_EVENTS: &[&str] = &[
+    "added",
+    "removed",
+    "activated",
+    "caption",
+    "geometry",
+    "desktop",
+];
+// Events that are connected to every window, instead of the workspace.
+pub const WINDOW_EVENTS: &[&str] = &["caption", "geometry", "desktop"];
+
+pub const STEP_WATCH: &str = r#"
+    output_debug("STEP watch")
+    {
+        const watch_output = (event, window, data = {}) => {
+            data.event = event;
+            data.time = new Date().toISOString();
+            if (window) {
+                data.window = window_info(window);
+            }
+            output_result(JSON.stringify(data));
+        };
+        {{#if per_window}}
+        const watch_connect = (window) => {
+            {{#if event_caption}}
+            window.captionChanged.connect(() => watch_output("caption", window));
+            {{/if}}
+            {{#if event_geometry}}
+            window.frameGeometryChanged.connect(() => watch_output("geometry", window));
+            {{/if}}
+            {{#if event_desktop}}
+            window.desktopsChanged.connect(() => watch_output("desktop", window));
+            {{/if}}
+        };
+        workspace_windowList().forEach(watch_connect);
+        workspace.windowAdded.connect(watch_connect);
+        {{/if}}
+        {{#if event_added}}
+        workspace.windowAdded.connect((window) => watch_output("added", window));
+        {{/if}}
+        {{#if event_removed}}
+        workspace.windowRemoved.connect((window) => watch_output("removed", window));
+        {{/if}}
+        {{#if event_activated}}
+        workspace.windowActivated.connect((window) => watch_output("activated", window));
+        {{/if}}
+        {{#if event_desktop}}
+        workspace.currentDesktopChanged.connect(
+            () => watch_output("desktop", null, {desktop: desktop_output(workspace.currentDesktop)}));
+        {{/if}}
+    }
+"#;
+
+// Runs the event handler chain with the event's window as the window stack.
+// The handler is only run for the selected windows, if any.
+pub const STEP_BEHAVE: &str = r#"
+    output_debug("STEP behave")
+    {
+        const behave_ids = (() => {
+            {{#if window_id}}
+            return [{{{js window_id}}}];
+            {{else if all_windows}}
+            return window_stack.map((w) => w.internalId.toString());
+            {{else if has_selection}}
+            const item_index = {{{item_index}}};
+            const window_index = item_index > 0 ? item_index - 1 : window_stack.length + item_index;
+            if (window_index >= window_stack.length || window_index < 0) {
+                output_error("Invalid window stack selection '%{{{item_index}}}' (out of range)");
+                return [];
+            }
+            return [window_stack[window_index].internalId.toString()];
+            {{else}}
+            return null;
+            {{/if}}
+        })();
+        const behave_matches = (window) =>
+            behave_ids == null || behave_ids.includes(window.internalId.toString());
+        const behave_run = (window) => {
+            if (!window || !behave_matches(window)) {
+                return;
+            }
+            var window_stack = [window];
+            try {
+                {{{handler}}}
+            } catch (e) {
+                output_error("Script error: " + e);
+            }
+        };
+        {{#if per_window}}
+        const behave_connect = (window) => {
+            if (!behave_matches(window)) {
+                return;
+            }
+            {{#if event_caption}}
+            window.captionChanged.connect(() => behave_run(window));
+            {{/if}}
+            {{#if event_geometry}}
+            window.frameGeometryChanged.connect(() => behave_run(window));
+            {{/if}}
+        };
+        workspace_windowList().forEach(behave_connect);
+        workspace.windowAdded.connect(behave_connect);
+        {{/if}}
+        {{#if event_added}}
+        workspace.windowAdded.connect(behave_run);
+        {{/if}}
+        {{#if event_removed}}
+        workspace.windowRemoved.connect(behave_run);
+        {{/if}}
+        {{#if event_activated}}
+        workspace.windowActivated.connect(behave_run);
+        {{/if}}
     }
 "#;
 
 pub const WINDOW_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
-    "getwindowname"         => "output_result(w.caption);",
-    "getwindowclassname"    => "output_result(w.resourceClass);",
-    "getwindowgeometry"     => "output_result(`Window ${w.internalId}`); output_result(`  Position: ${w.x},${w.y}`); output_result(`  Geometry: ${w.width}x${w.height}`);",
-    "getwindowid"           => "output_result(w.internalId);",
-    "getwindowpid"          => "output_result(w.pid);",
-    "windowminimize"        => "w.minimized = true;",
+    "getwindowname"         => r#"
+            {{#if shell}}
+            output_shell({WINDOW: w.internalId, NAME: w.caption});
+            {{else}}
+            output_query({id: w.internalId.toString(), caption: w.caption}, w.caption);
+            {{/if}}
+"#,
+    "getwindowclassname"    => "output_query({id: w.internalId.toString(), resource_class: w.resourceClass}, w.resourceClass);",
+    "getwindowgeometry"     => r#"
+            {{#if shell}}
+            output_shell({
+                WINDOW: w.internalId,
+                X: w.x,
+                Y: w.y,
+                WIDTH: w.width,
+                HEIGHT: w.height,
+                SCREEN: workspace.screens.indexOf(w.output),
+            });
+            {{else}}
+            output_query(
+                {
+                    id: w.internalId.toString(),
+                    geometry: window_info(w).geometry,
+                    output: window_info(w).output,
+                },
+                [`Window ${w.internalId}`, `  Position: ${w.x},${w.y}`, `  Geometry: ${w.width}x${w.height}`]);
+            {{/if}}
+"#,
+    "getwindowid"           => "output_query({id: w.internalId.toString()}, w.internalId);",
+    "getwindowpid"          => r#"
+            {{#if shell}}
+            output_shell({WINDOW: w.internalId, PID: w.pid});
+            {{else}}
+            output_query({id: w.internalId.toString(), pid: w.pid}, w.pid);
+            {{/if}}
+"#,
+    "windowminimize"        => r#"
+            {{#if sync}}
+            sync_pending.push({signal: w.minimizedChanged, check: () => w.minimized});
+            {{/if}}
+            w.minimized = true;
+"#,
     "windowraise"           => "workspace_raiseWindow(w);",
     "windowclose"           => "w.closeWindow();",
-    "windowactivate"        => "workspace_setActiveWindow(w);",
+    "windowactivate"        => r#"
+            {{#if sync}}
+            sync_pending.push({
+                signal: workspace.windowActivated,
+                check: () => workspace_activeWindow() != null
+                    && workspace_activeWindow().internalId.toString() == w.internalId.toString(),
+            });
+            {{/if}}
+            workspace_setActiveWindow(w);
+"#,
     "windowsize"            => r#"
             output_debug(`Window: ${w.frameGeometry}`);
             output_debug(`Screen: ${workspace.virtualScreenSize}`);
@@ -194,6 +918,15 @@ pub const WINDOW_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
             {{#if y_percent}}q.height=workspace.virtualScreenSize.height*{{{y_percent}}}/100;{{/if}}
             {{#if x}}q.width={{{x}}};{{/if}}
             {{#if y}}q.height={{{y}}};{{/if}}
+            {{#if sync}}
+            if (q.width != w.width || q.height != w.height) {
+                const [width, height] = [w.width, w.height];
+                sync_pending.push({
+                    signal: w.frameGeometryChanged,
+                    check: () => w.width != width || w.height != height,
+                });
+            }
+            {{/if}}
             w.frameGeometry = q;
 "#,
     "windowmove"            => r#"
@@ -204,6 +937,15 @@ pub const WINDOW_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
             {{#if y_percent}}q.y={{#if relative}}w.y+{{/if}}workspace.virtualScreenSize.height*{{{y_percent}}}/100;{{/if}}
             {{#if x}}q.x={{#if relative}}w.x+{{/if}}{{{x}}};{{/if}}
             {{#if y}}q.y={{#if relative}}w.y+{{/if}}{{{y}}};{{/if}}
+            {{#if sync}}
+            if (q.x != w.x || q.y != w.y) {
+                const [x, y] = [w.x, w.y];
+                sync_pending.push({
+                    signal: w.frameGeometryChanged,
+                    check: () => w.x != x || w.y != y,
+                });
+            }
+            {{/if}}
             w.frameGeometry = q;
 "#,
     "windowstate"           => r#"
@@ -214,8 +956,74 @@ pub const WINDOW_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
                 w.setMaximize(maximizeVert, maximizeHorz);
             }
 "#,
-    "get_desktop_for_window"=> "output_result(window_x11DesktopIds(w)[0]);",
-    "set_desktop_for_window"=> "window_setX11DesktopId(w, {{{desktop_id}}});",
+    "get_desktop_for_window"=> r#"
+            {{#if all}}
+            const desktops = window_desktops(w).map(desktop_output);
+            {{#if shell}}
+            output_shell({WINDOW: w.internalId, DESKTOPS: desktops.join(" ")});
+            {{else}}
+            output_query({id: w.internalId.toString(), desktops: desktops}, desktops);
+            {{/if}}
+            {{else}}
+            {{#if shell}}
+            output_shell({WINDOW: w.internalId, DESKTOP: desktop_output(w.desktops[0])});
+            {{else}}
+            output_query({id: w.internalId.toString(), desktop: desktop_output(w.desktops[0])}, desktop_output(w.desktops[0]));
+            {{/if}}
+            {{/if}}
+"#,
+    "get_activities_for_window"=> r#"
+            const activities = w.activities.length ? w.activities : workspace.activities;
+            output_query({id: w.internalId.toString(), activities: activities}, activities);
+"#,
+    "set_activities_for_window"=> "window_setActivities(w, activity_list, {{{js activities}}});",
+    "add_desktop_for_window"=> "window_addDesktop(w, {{{js desktop}}});",
+    "remove_desktop_for_window"=> "window_removeDesktop(w, {{{js desktop}}});",
+    "set_desktop_for_window"=> r#"
+            {{#if relative}}
+            window_setDesktop(w, workspace_desktopRelative(
+                w.desktops[0] || workspace.currentDesktop, {{{delta}}}, {{{wrap}}}).id);
+            {{else if direction}}
+            window_setDesktop(w, workspace_desktopInDirection(
+                w.desktops[0] || workspace.currentDesktop, {{{js direction}}}, {{{wrap}}}).id);
+            {{else}}
+            window_setDesktop(w, {{{js desktop}}});
+            {{/if}}
+"#,
+};
+
+// Keys of `search --sort`, implemented by `window_sortKeys`.
+pub const SORT_KEYS: &[&str] = &[
+    "stacking",
+    "focus-history",
+    "x",
+    "y",
+    "area",
+    "title",
+    "class",
+    "pid",
+];
+
+// Window types of `search --type`, and the KWin properties that are true for
+// windows of that type.
+pub const WINDOW_TYPES: phf::Map<&'static str, &'static str> = phf::phf_map! {
+    "normal" => "normalWindow",
+    "dialog" => "dialog",
+    "utility" => "utility",
+    "toolbar" => "toolbar",
+    "menu" => "menu",
+    "splash" => "splash",
+    "dock" => "dock",
+    "desktop" => "desktopWindow",
+    "notification" => "notification",
+    "critical_notification" => "criticalNotification",
+    "osd" => "onScreenDisplay",
+    "popup_menu" => "popupMenu",
+    "dropdown_menu" => "dropdownMenu",
+    "tooltip" => "tooltip",
+    "combobox" => "comboBox",
+    "dnd" => "dndIcon",
+    "applet_popup" => "appletPopup",
 };
 
 pub const WINDOWSTATE_PROPERTIES: phf::Map<&'static str, &'static str> = phf::phf_map! {
@@ -236,9 +1044,67 @@ pub const STEP_GLOBAL_ACTION: &str = r#"
 "#;
 
 pub const GLOBAL_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
-    "get_desktop"           => "output_result(workspace_currentDesktop());",
-    "set_desktop"           => "workspace_setCurrentDesktop({{{n}}});",
-    "get_num_desktops"      => "output_result(workspace_numDesktops());",
+    "get_desktop"           => "output_query({desktop: desktop_output(workspace.currentDesktop)}, desktop_output(workspace.currentDesktop));",
+    "set_desktop"           => r#"
+        {{#if relative}}
+        workspace.currentDesktop = workspace_desktopRelative(workspace.currentDesktop, {{{delta}}}, {{{wrap}}});
+        {{else if direction}}
+        workspace.currentDesktop = workspace_desktopInDirection(workspace.currentDesktop, {{{js direction}}}, {{{wrap}}});
+        {{else}}
+        workspace_setCurrentDesktop({{{js desktop}}});
+        {{/if}}
+    "#,
+    "get_num_desktops"      => "output_query({num_desktops: workspace_numDesktops()}, workspace_numDesktops());",
+    "set_num_desktops"      => "workspace_setNumDesktops({{{n}}});",
+    "desktop_create"        => r#"
+        {
+            const position = {{{position}}} > 0 ? {{{position}}} - 1 : workspace.desktops.length;
+            workspace.createDesktop(Math.min(position, workspace.desktops.length), {{{js name}}});
+        }
+    "#,
+    "desktop_remove"        => r#"
+        {
+            const d = workspace_findDesktop({{{js desktop}}});
+            if (d) {
+                workspace.removeDesktop(d);
+            }
+        }
+    "#,
+    "desktop_rename"        => r#"
+        {
+            const d = workspace_findDesktop({{{js desktop}}});
+            if (d) {
+                callDBus("org.kde.KWin", "/VirtualDesktopManager", "org.kde.KWin.VirtualDesktopManager",
+                         "setDesktopName", d.id, {{{js name}}});
+            }
+        }
+    "#,
+    "list_desktops"         => r#"
+        for (const d of workspace.desktops) {
+            const info = desktop_info(d);
+            const current = info.current ? "*" : "-";
+            output_query(info, `${info.number}\t${current}\t${info.id}\t${info.name}`);
+        }
+    "#,
+    "get_activity"          => r#"
+        {
+            const a = activities_find(activity_list, workspace.currentActivity) || {id: workspace.currentActivity, name: ""};
+            output_query({id: a.id, name: a.name}, a.id);
+        }
+    "#,
+    "set_activity"          => r#"
+        {
+            const a = activities_find(activity_list, {{{js activity}}});
+            if (a) {
+                workspace.currentActivity = a.id;
+            }
+        }
+    "#,
+    "list_activities"       => r#"
+        for (const a of activity_list) {
+            output_query({id: a.id, name: a.name, current: a.id == workspace.currentActivity}, `${a.id}\t${a.name}`);
+        }
+    "#,
     "getmouselocation"      => r#"
         let p = workspace.cursorPos;
         let screen = workspace.screenAt(p);
@@ -251,12 +1117,11 @@ pub const GLOBAL_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
             window_stack.push(window_list[0]);
         }
         {{#if shell}}
-        output_result("X="+p.x);
-        output_result("Y="+p.y);
-        output_result("SCREEN="+screen_id);
-        output_result("WINDOW="+window_id);
+        output_shell({X: p.x, Y: p.y, SCREEN: screen_id, WINDOW: window_id});
         {{else}}
-        output_result(`x:${p.x} y:${p.y} screen:${screen_id} window:${window_id}`);
+        output_query(
+            {x: p.x, y: p.y, screen: screen_id, window: window_id.toString()},
+            `x:${p.x} y:${p.y} screen:${screen_id} window:${window_id}`);
         {{/if}}
     "#,
 };
@@ -0,0 +1,358 @@
+use anyhow::anyhow;
+use serde_json::json;
+
+use crate::engine::{new_registry, run_step, windowstate_script};
+use crate::templates::WINDOW_ACTIONS;
+use crate::{Error, Rect, Result, WindowInfo};
+
+const STEP_WINDOW_ACTION: &str = r#"
+    output_debug("STEP {{{step_name}}}")
+    let w = workspace_windowList().find((w) => w.internalId == {{{js window_id}}});
+    if (w == null) {
+        output_result(JSON.stringify(false));
+    } else {
+        {{{action}}}
+        output_result(JSON.stringify(true));
+    }
+"#;
+
+/// A window state that can be set with [`Window::set_state`]. These are the
+/// properties accepted by the `windowstate` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowState {
+    Above,
+    Below,
+    SkipTaskbar,
+    SkipPager,
+    Fullscreen,
+    Shaded,
+    DemandsAttention,
+    NoBorder,
+    Minimized,
+    MaximizedVert,
+    MaximizedHorz,
+    Maximized,
+}
+
+impl WindowState {
+    /// The property name as used by `windowstate --add PROPERTY`.
+    pub fn property(self) -> &'static str {
+        match self {
+            WindowState::Above => "above",
+            WindowState::Below => "below",
+            WindowState::SkipTaskbar => "skip_taskbar",
+            WindowState::SkipPager => "skip_pager",
+            WindowState::Fullscreen => "fullscreen",
+            WindowState::Shaded => "shaded",
+            WindowState::DemandsAttention => "demands_attention",
+            WindowState::NoBorder => "no_border",
+            WindowState::Minimized => "minimized",
+            WindowState::MaximizedVert => "maximized_vert",
+            WindowState::MaximizedHorz => "maximized_horz",
+            WindowState::Maximized => "maximized",
+        }
+    }
+}
+
+/// Target desktop for [`Window::set_desktop`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Desktop {
+    /// The desktop with the given X11 desktop number (starting at 1).
+    Number(u32),
+    /// The desktop with the given name, as output by `list_desktops`.
+    Name(String),
+    /// The desktop with the given KWin id, as output by `list_desktops`.
+    Id(String),
+    /// The current desktop.
+    Current,
+    /// All desktops.
+    All,
+}
+
+/// An action of [`Window`], rendered from the `WINDOW_ACTIONS` template of its
+/// command.
+#[derive(Debug, Clone, PartialEq)]
+enum WindowAction {
+    Activate,
+    Raise,
+    Minimize,
+    Close,
+    MoveTo(i32, i32),
+    Resize(i32, i32),
+    SetGeometry(Rect),
+    SetState(WindowState, bool),
+    SetDesktop(Desktop),
+}
+
+impl WindowAction {
+    /// Returns the step name and the JavaScript of the action, run with the
+    /// window in `w`.
+    fn script(self) -> Result<(&'static str, String)> {
+        let (command, params) = match self {
+            WindowAction::Activate => ("windowactivate", json!({})),
+            WindowAction::Raise => ("windowraise", json!({})),
+            WindowAction::Minimize => ("windowminimize", json!({})),
+            WindowAction::Close => ("windowclose", json!({})),
+            WindowAction::MoveTo(x, y) => (
+                "windowmove",
+                json!({
+                    "relative": false,
+                    "x": x.to_string(),
+                    "y": y.to_string(),
+                    "x_percent": "",
+                    "y_percent": "",
+                }),
+            ),
+            WindowAction::Resize(width, height) => (
+                "windowsize",
+                json!({
+                    "x": width.to_string(),
+                    "y": height.to_string(),
+                    "x_percent": "",
+                    "y_percent": "",
+                }),
+            ),
+            WindowAction::SetGeometry(rect) => {
+                let Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                } = rect;
+                if ![x, y, width, height].iter().all(|v| v.is_finite()) {
+                    return Err(Error::Other(anyhow!("invalid geometry {rect:?}")));
+                }
+                return Ok((
+                    "setgeometry",
+                    format!(
+                        "w.frameGeometry = {{x: {x}, y: {y}, width: {width}, height: {height}}};"
+                    ),
+                ));
+            }
+            WindowAction::SetState(state, enabled) => {
+                let op = if enabled { "add" } else { "remove" };
+                let windowstate = windowstate_script(op, state.property()).map_err(Error::from)?;
+                ("windowstate", json!({ "windowstate": windowstate }))
+            }
+            WindowAction::SetDesktop(desktop) => {
+                // See `window_setDesktop` and `workspace_findDesktop`. A name
+                // or id is passed as an object, so that it is only matched
+                // against that property.
+                let desktop = match desktop {
+                    Desktop::Number(n) => json!(n),
+                    Desktop::Name(name) => json!({ "name": name }),
+                    Desktop::Id(id) => json!({ "id": id }),
+                    Desktop::Current => json!(-1),
+                    Desktop::All => json!(-2),
+                };
+                (
+                    "set_desktop_for_window",
+                    json!({
+                        "desktop": desktop,
+                        "relative": false,
+                        "direction": "",
+                        "delta": 0,
+                        "wrap": false,
+                    }),
+                )
+            }
+        };
+        let action = new_registry()
+            .render_template(WINDOW_ACTIONS.get(command).unwrap(), &params)
+            .map_err(|err| Error::Other(err.into()))?;
+        Ok((command, action))
+    }
+}
+
+/// A handle to a KWin window, identified by its internal id.
+///
+/// The handle does not keep the window alive. Every method runs a KWin script
+/// that looks the window up again, and fails with [`Error::WindowNotFound`]
+/// if it is gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    id: String,
+}
+
+impl Window {
+    /// Creates a handle from a KWin internal id, e.g.
+    /// `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`.
+    pub fn new(id: impl Into<String>) -> Self {
+        Window { id: id.into() }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn activate(&self) -> Result<()> {
+        self.run_action(WindowAction::Activate)
+    }
+
+    pub fn raise(&self) -> Result<()> {
+        self.run_action(WindowAction::Raise)
+    }
+
+    pub fn minimize(&self) -> Result<()> {
+        self.run_action(WindowAction::Minimize)
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.run_action(WindowAction::Close)
+    }
+
+    pub fn move_to(&self, x: i32, y: i32) -> Result<()> {
+        self.run_action(WindowAction::MoveTo(x, y))
+    }
+
+    pub fn resize(&self, width: i32, height: i32) -> Result<()> {
+        self.run_action(WindowAction::Resize(width, height))
+    }
+
+    /// Moves and resizes the window in one step. Fails if a coordinate is not
+    /// finite.
+    pub fn set_geometry(&self, rect: Rect) -> Result<()> {
+        self.run_action(WindowAction::SetGeometry(rect))
+    }
+
+    pub fn set_state(&self, state: WindowState, enabled: bool) -> Result<()> {
+        self.run_action(WindowAction::SetState(state, enabled))
+    }
+
+    pub fn set_desktop(&self, desktop: Desktop) -> Result<()> {
+        self.run_action(WindowAction::SetDesktop(desktop))
+    }
+
+    fn run_action(&self, action: WindowAction) -> Result<()> {
+        let (step_name, action) = action.script()?;
+        let params = json!({
+            "step_name": step_name,
+            "window_id": self.id,
+            "action": action,
+        });
+        let payload = run_step(
+            &format!("kdotool::Window::{step_name}"),
+            STEP_WINDOW_ACTION,
+            &params,
+        )?;
+        if payload == "true" {
+            Ok(())
+        } else {
+            Err(Error::WindowNotFound(self.id.clone()))
+        }
+    }
+}
+
+impl From<&WindowInfo> for Window {
+    fn from(info: &WindowInfo) -> Self {
+        Window::new(info.id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(action: WindowAction) -> String {
+        action.script().unwrap().1
+    }
+
+    #[test]
+    fn move_and_resize_set_frame_geometry() {
+        let js = script(WindowAction::MoveTo(10, -20));
+        assert!(js.contains("q.x=10;"));
+        assert!(js.contains("q.y=-20;"));
+        assert!(!js.contains("w.x+"));
+
+        let js = script(WindowAction::Resize(800, 600));
+        assert!(js.contains("q.width=800;"));
+        assert!(js.contains("q.height=600;"));
+        assert!(!js.contains("virtualScreenSize.width*"));
+    }
+
+    #[test]
+    fn set_state_adds_or_removes_property() {
+        let add = script(WindowAction::SetState(WindowState::Above, true));
+        let remove = script(WindowAction::SetState(WindowState::Above, false));
+        assert!(add.contains("w.keepAbove = true"), "{add}");
+        assert!(remove.contains("w.keepAbove = false"), "{remove}");
+
+        let js = script(WindowAction::SetState(WindowState::Maximized, true));
+        assert!(
+            js.contains("let t = true; maximizeVert = maximizeHorz = t;"),
+            "{js}"
+        );
+        assert!(js.contains("w.setMaximize(maximizeVert, maximizeHorz);"));
+    }
+
+    #[test]
+    fn set_desktop_passes_desktop() {
+        let cases = [
+            (Desktop::Number(3), "window_setDesktop(w, 3);"),
+            (Desktop::Current, "window_setDesktop(w, -1);"),
+            (Desktop::All, "window_setDesktop(w, -2);"),
+            (
+                Desktop::Name("Mail \"2\"".into()),
+                r#"window_setDesktop(w, {"name":"Mail \"2\""});"#,
+            ),
+            (
+                Desktop::Id("d-1".into()),
+                r#"window_setDesktop(w, {"id":"d-1"});"#,
+            ),
+        ];
+        for (desktop, expected) in cases {
+            assert_eq!(script(WindowAction::SetDesktop(desktop)).trim(), expected);
+        }
+    }
+
+    #[test]
+    fn all_actions_get_their_params() {
+        let actions = [
+            WindowAction::Activate,
+            WindowAction::Raise,
+            WindowAction::Minimize,
+            WindowAction::Close,
+            WindowAction::MoveTo(0, 0),
+            WindowAction::Resize(1, 1),
+            WindowAction::SetState(WindowState::Shaded, true),
+            WindowAction::SetDesktop(Desktop::Number(1)),
+        ];
+        for action in actions {
+            let js = script(action.clone());
+            assert!(
+                !js.contains("null") && !js.contains("undefined"),
+                "{action:?}: {js}"
+            );
+        }
+    }
+
+    #[test]
+    fn simple_actions_render() {
+        assert_eq!(script(WindowAction::Raise), "workspace_raiseWindow(w);");
+        assert_eq!(script(WindowAction::Close), "w.closeWindow();");
+        assert!(script(WindowAction::Activate).contains("workspace_setActiveWindow(w);"));
+        assert!(script(WindowAction::Minimize).contains("w.minimized = true;"));
+        assert_eq!(
+            script(WindowAction::SetGeometry(Rect {
+                x: 1.0,
+                y: 2.0,
+                width: 640.5,
+                height: 480.0,
+            })),
+            "w.frameGeometry = {x: 1, y: 2, width: 640.5, height: 480};"
+        );
+    }
+
+    #[test]
+    fn set_geometry_rejects_non_finite_values() {
+        for bad in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let rect = Rect {
+                x: 0.0,
+                y: 0.0,
+                width: bad,
+                height: 100.0,
+            };
+            assert!(WindowAction::SetGeometry(rect).script().is_err());
+        }
+    }
+}
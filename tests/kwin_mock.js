@@ -0,0 +1,264 @@
+// A small mock of the KWin 6 scripting API, used by tests/scripts.rs to run
+// generated scripts with node.
+//
+// Usage: node kwin_mock.js SCRIPT
+//
+// Every callDBus() is printed to stdout as a JSON line. The JavaScript in
+// $KWIN_MOCK_SETUP is run before the script, and the one in
+// $KWIN_MOCK_SCENARIO after it, e.g. to add windows or emit signals. Both
+// can use the `mock` object.
+
+const fs = require("fs");
+const vm = require("vm");
+
+// Property changes of windows take effect asynchronously, like in KWin.
+const DELAY = 5;
+
+function Signal() {
+    const handlers = [];
+    return {
+        connect: (f) => handlers.push(f),
+        disconnect: (f) => {
+            const i = handlers.indexOf(f);
+            if (i >= 0) {
+                handlers.splice(i, 1);
+            }
+        },
+        emit: (...args) => handlers.slice().forEach((f) => f(...args)),
+    };
+}
+
+global.print = (...args) => process.stderr.write(args.join(" ") + "\n");
+global.callDBus = (service, path, iface, method, ...args) => {
+    const callback = typeof args[args.length - 1] == "function" ? args.pop() : null;
+    console.log(JSON.stringify({ service, path, iface, method, args: args.map(String) }));
+    if (callback) {
+        setTimeout(() => callback(...reply(method, args)), 1);
+    }
+};
+global.registerShortcut = (name, text, keys, callback) => {};
+global.QTimer = function () {
+    this.interval = 0;
+    this.singleShot = false;
+    this.timeout = Signal();
+    let handle = null;
+    this.start = (ms) => {
+        if (ms !== undefined) {
+            this.interval = ms;
+        }
+        this.stop();
+        handle = (this.singleShot ? setTimeout : setInterval)(() => this.timeout.emit(), this.interval);
+    };
+    this.stop = () => {
+        clearTimeout(handle);
+        clearInterval(handle);
+        handle = null;
+    };
+};
+
+const activities = [
+    { id: "a1a1a1a1-0000-0000-0000-000000000001", name: "Default" },
+    { id: "b2b2b2b2-0000-0000-0000-000000000002", name: "Work" },
+];
+
+function reply(method, args) {
+    if (method == "ActivityName") {
+        const a = activities.find((a) => a.id == args[0]);
+        return [a ? a.name : ""];
+    }
+    return [];
+}
+
+const outputs = [
+    { name: "DP-1", geometry: { x: 0, y: 0, width: 1920, height: 1080 } },
+    { name: "HDMI-A-1", geometry: { x: 1920, y: 0, width: 1920, height: 1080 } },
+];
+
+let desktopCount = 0;
+function makeDesktop(name) {
+    desktopCount++;
+    const n = desktopCount.toString().padStart(2, "0");
+    return { id: `d00000${n}-0000-0000-0000-0000000000${n}`, name, x11DesktopNumber: 0 };
+}
+
+let windowCount = 0;
+function makeWindow(props) {
+    windowCount++;
+    const id = `{${windowCount.toString().padStart(8, "0")}-aaaa-bbbb-cccc-dddddddddddd}`;
+    const w = Object.assign({
+        caption: "window " + windowCount,
+        resourceClass: "class" + windowCount,
+        resourceName: "name" + windowCount,
+        windowRole: "",
+        pid: 1000 + windowCount,
+        desktopFileName: "",
+        normalWindow: true, dialog: false, utility: false, toolbar: false, menu: false,
+        splash: false, dock: false, desktopWindow: false, notification: false,
+        criticalNotification: false, onScreenDisplay: false, popupMenu: false,
+        dropdownMenu: false, tooltip: false, comboBox: false, dndIcon: false,
+        appletPopup: false, specialWindow: false,
+        skipTaskbar: false, skipPager: false, skipSwitcher: false,
+        keepAbove: false, keepBelow: false, fullScreen: false, shade: false,
+        noBorder: false, demandsAttention: false, maximizeMode: 0, hidden: false,
+        onAllDesktops: false, output: outputs[0], activities: [],
+    }, props);
+    delete w.geometry;
+    delete w.desktops;
+    delete w.minimized;
+    w.internalId = { toString: () => id, [Symbol.toPrimitive]: () => id, toJSON: () => id };
+    for (const signal of ["captionChanged", "windowClassChanged", "frameGeometryChanged",
+                          "minimizedChanged", "desktopsChanged", "activitiesChanged",
+                          "outputChanged", "closed"]) {
+        w[signal] = Signal();
+    }
+    let geometry = props.geometry || { x: 0, y: 0, width: 400, height: 300 };
+    let minimized = !!props.minimized;
+    let desktops = props.desktops || [workspace.desktops[0]];
+    let caption = w.caption;
+    Object.defineProperty(w, "caption", {
+        get: () => caption,
+        set: (c) => { caption = c; w.captionChanged.emit(); },
+    });
+    Object.defineProperty(w, "frameGeometry", {
+        get: () => Object.assign({}, geometry),
+        set: (g) => setTimeout(() => {
+            const old = geometry;
+            geometry = { x: g.x, y: g.y, width: g.width, height: g.height };
+            w.frameGeometryChanged.emit(old);
+        }, DELAY),
+    });
+    for (const key of ["x", "y", "width", "height"]) {
+        Object.defineProperty(w, key, { get: () => geometry[key] });
+    }
+    Object.defineProperty(w, "minimized", {
+        get: () => minimized,
+        set: (v) => setTimeout(() => { minimized = v; w.minimizedChanged.emit(); }, DELAY),
+    });
+    Object.defineProperty(w, "desktops", {
+        get: () => desktops,
+        set: (v) => { desktops = v; w.onAllDesktops = v.length == 0; w.desktopsChanged.emit(); },
+    });
+    w.setMaximize = (vertically, horizontally) => {
+        w.maximizeMode = (vertically ? 1 : 0) | (horizontally ? 2 : 0);
+    };
+    w.closeWindow = () => mock.remove(w);
+    return w;
+}
+
+let windows = [];
+let stacking = [];
+let active = null;
+let currentDesktop = null;
+
+const workspace = global.workspace = {
+    desktops: [],
+    desktopGridWidth: 2,
+    desktopGridHeight: 2,
+    activities: activities.map((a) => a.id),
+    currentActivity: activities[0].id,
+    screens: outputs,
+    virtualScreenSize: { width: 3840, height: 1080 },
+    cursorPos: { x: 100, y: 100 },
+    windowAdded: Signal(),
+    windowRemoved: Signal(),
+    windowActivated: Signal(),
+    currentDesktopChanged: Signal(),
+    desktopsChanged: Signal(),
+    currentActivityChanged: Signal(),
+    windowList: () => windows.slice(),
+    get stackingOrder() { return stacking.slice(); },
+    get activeWindow() { return active; },
+    set activeWindow(w) {
+        setTimeout(() => { active = w; mock.raise(w); workspace.windowActivated.emit(w); }, DELAY);
+    },
+    get currentDesktop() { return currentDesktop; },
+    set currentDesktop(d) {
+        const old = currentDesktop;
+        currentDesktop = d;
+        workspace.currentDesktopChanged.emit(old);
+    },
+    raiseWindow: (w) => mock.raise(w),
+    screenAt: (p) => outputs[p.x >= 1920 ? 1 : 0],
+    windowAt: (p) => stacking.slice().reverse().filter(
+        (w) => p.x >= w.x && p.x < w.x + w.width && p.y >= w.y && p.y < w.y + w.height),
+    createDesktop: (position, name) => {
+        workspace.desktops.splice(position, 0, makeDesktop(name));
+        mock.renumber();
+    },
+    removeDesktop: (d) => {
+        workspace.desktops = workspace.desktops.filter((x) => x !== d);
+        mock.renumber();
+    },
+};
+
+const mock = global.mock = {
+    makeWindow,
+    outputs,
+    activities,
+    get windows() { return windows; },
+    // Adds a window, like an application mapping it.
+    add: (props) => {
+        const w = makeWindow(props);
+        windows.push(w);
+        stacking.push(w);
+        workspace.windowAdded.emit(w);
+        return w;
+    },
+    remove: (w) => {
+        windows = windows.filter((x) => x !== w);
+        stacking = stacking.filter((x) => x !== w);
+        workspace.windowRemoved.emit(w);
+        w.closed.emit();
+    },
+    raise: (w) => {
+        stacking = stacking.filter((x) => x !== w).concat([w]);
+    },
+    activate: (w) => {
+        active = w;
+        mock.raise(w);
+        workspace.windowActivated.emit(w);
+    },
+    renumber: () => {
+        workspace.desktops.forEach((d, i) => d.x11DesktopNumber = i + 1);
+        workspace.desktopsChanged.emit();
+    },
+};
+
+for (const name of ["Desktop 1", "Desktop 2", "Desktop 3", "Desktop 4"]) {
+    workspace.desktops.push(makeDesktop(name));
+}
+mock.renumber();
+currentDesktop = workspace.desktops[0];
+
+// The windows every test starts with, from bottom to top in stacking order.
+// The first one is active.
+for (const props of [
+    { caption: "Konsole - ~", resourceClass: "org.kde.konsole", resourceName: "konsole",
+      desktopFileName: "org.kde.konsole", geometry: { x: 0, y: 0, width: 800, height: 600 } },
+    { caption: "Mozilla Firefox", resourceClass: "firefox", resourceName: "Navigator",
+      desktopFileName: "firefox", geometry: { x: 100, y: 50, width: 1200, height: 900 },
+      output: outputs[1], desktops: [workspace.desktops[1]] },
+    { caption: "Private Browsing - Mozilla Firefox", resourceClass: "firefox",
+      resourceName: "Navigator", minimized: true,
+      geometry: { x: 10, y: 10, width: 500, height: 500 } },
+    { caption: "plasmashell", resourceClass: "plasmashell", resourceName: "plasmashell",
+      normalWindow: false, dock: true, onAllDesktops: true, desktops: [],
+      geometry: { x: 0, y: 1040, width: 1920, height: 40 } },
+    { caption: "Konsole - build", resourceClass: "org.kde.konsole", resourceName: "konsole",
+      keepAbove: true, activities: [activities[1].id],
+      geometry: { x: 300, y: 200, width: 640, height: 480 } },
+]) {
+    mock.add(props);
+}
+active = windows[0];
+
+// Scripts that wait for events (e.g. `watch`) never finish on their own.
+setTimeout(() => process.exit(0), +(process.env.KWIN_MOCK_RUN_TIME || 2000)).unref();
+
+if (process.env.KWIN_MOCK_SETUP) {
+    vm.runInThisContext(process.env.KWIN_MOCK_SETUP, { filename: "setup.js" });
+}
+vm.runInThisContext(fs.readFileSync(process.argv[2], "utf8"), { filename: "script.js" });
+if (process.env.KWIN_MOCK_SCENARIO) {
+    vm.runInThisContext(process.env.KWIN_MOCK_SCENARIO, { filename: "scenario.js" });
+}
@@ -0,0 +1,429 @@
+//! Runs generated scripts with node against a mock of the KWin scripting API
+//! (tests/kwin_mock.js). The tests are skipped if node is not installed.
+
+use std::io::Write;
+use std::process::Command;
+
+use kdotool::WindowInfo;
+use kdotool::engine::{Globals, generate_script};
+use lexopt::Parser;
+use serde_json::Value;
+
+const MOCK: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/kwin_mock.js");
+
+/// What a script sent back, in order.
+#[derive(Debug, Default)]
+struct Output {
+    results: Vec<String>,
+    errors: Vec<String>,
+}
+
+/// Generates the script for `args` and runs it in the mock, between `setup`
+/// and `scenario`. Returns `None` if node is not available.
+fn run_with(args: &[&str], json: bool, setup: &str, scenario: &str) -> Option<Output> {
+    if Command::new("node").arg("--version").output().is_err() {
+        eprintln!("node not found, skipping");
+        return None;
+    }
+    let globals = Globals {
+        dbus_addr: "test".into(),
+        json,
+        timeout_ms: 1000,
+        ..Globals::new(&args.join(" ")).unwrap()
+    };
+    let script = generate_script(&globals, Parser::from_args(&args[1..]), args[0]).unwrap();
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(script.as_bytes()).unwrap();
+
+    let out = Command::new("node")
+        .arg(MOCK)
+        .arg(file.path())
+        .env("KWIN_MOCK_SETUP", setup)
+        .env("KWIN_MOCK_SCENARIO", scenario)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let mut output = Output::default();
+    for line in String::from_utf8(out.stdout).unwrap().lines() {
+        let call: Value = serde_json::from_str(line).unwrap();
+        if call["service"] != "test" {
+            continue;
+        }
+        let arg = call["args"][0].as_str().unwrap_or_default().to_string();
+        match call["method"].as_str().unwrap() {
+            "result" => output.results.push(arg),
+            "error" => output.errors.push(arg),
+            _ => {}
+        }
+    }
+    Some(output)
+}
+
+fn run(args: &[&str]) -> Option<Output> {
+    run_with(args, false, "", "")
+}
+
+/// Runs `args` with `--json` and parses every result.
+fn run_json(args: &[&str]) -> Option<Vec<Value>> {
+    let output = run_with(args, true, "", "")?;
+    assert_eq!(output.errors, Vec::<String>::new());
+    Some(
+        output
+            .results
+            .iter()
+            .map(|r| serde_json::from_str(r).unwrap())
+            .collect(),
+    )
+}
+
+#[test]
+fn search_prints_window_ids() {
+    let Some(output) = run(&["search", "--class", "firefox"]) else {
+        return;
+    };
+    assert_eq!(
+        output.results,
+        [
+            "{00000002-aaaa-bbbb-cccc-dddddddddddd}",
+            "{00000003-aaaa-bbbb-cccc-dddddddddddd}",
+        ]
+    );
+}
+
+#[test]
+fn query_commands_output_window_info_fields() {
+    let Some(windows) = run_json(&["search", "--class", "firefox"]) else {
+        return;
+    };
+    assert_eq!(windows.len(), 2);
+    let info = &windows[0];
+    let window: WindowInfo = serde_json::from_value(info.clone()).unwrap();
+    let id = window.id.as_str();
+
+    for command in [
+        "getwindowname",
+        "getwindowclassname",
+        "getwindowgeometry",
+        "getwindowpid",
+        "getwindowid",
+    ] {
+        let results = run_json(&[command, id]).unwrap();
+        assert_eq!(results.len(), 1, "{command}");
+        let fields = results[0].as_object().unwrap();
+        assert!(fields.len() > 1 || command == "getwindowid", "{command}");
+        for (key, value) in fields {
+            assert_eq!(&info[key], value, "{command}: {key}");
+        }
+    }
+
+    let results = run_json(&["get_desktop_for_window", id]).unwrap();
+    assert_eq!(results[0]["id"], id);
+    assert_eq!(results[0]["desktop"], window.desktops[0]);
+}
+
+#[test]
+fn shell_output_survives_eval() {
+    let caption = "it's $HOME `id`\\n\nsecond  line ";
+    let setup = format!(
+        "mock.add({{caption: {}}});",
+        serde_json::to_string(caption).unwrap()
+    );
+    let id = "{00000006-aaaa-bbbb-cccc-dddddddddddd}";
+    let Some(output) = run_with(&["getwindowname", "--shell", id], false, &setup, "") else {
+        return;
+    };
+    assert_eq!(output.results.len(), 2);
+    assert!(output.results[0].starts_with("WINDOW="));
+
+    let out = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "{}\nprintf '%s|%s' \"$WINDOW\" \"$NAME\"",
+            output.results.join("\n")
+        ))
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{id}|{caption}")
+    );
+}
+
+const WATCH_SCENARIO: &str = r#"
+    const w = mock.add({caption: "new"});
+    mock.activate(w);
+    w.caption = "renamed";
+    w.frameGeometry = {x: 1, y: 2, width: 3, height: 4};
+    w.desktops = [workspace.desktops[2]];
+    workspace.currentDesktop = workspace.desktops[1];
+    setTimeout(() => mock.remove(w), 50);
+"#;
+
+/// Runs `watch` with `args` through [`WATCH_SCENARIO`], returning the events.
+fn watch(args: &[&str]) -> Option<Vec<Value>> {
+    let args: Vec<&str> = ["watch"].iter().chain(args).copied().collect();
+    let output = run_with(&args, false, "", WATCH_SCENARIO)?;
+    assert_eq!(output.errors, Vec::<String>::new());
+    Some(
+        output
+            .results
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect(),
+    )
+}
+
+#[test]
+fn watch_outputs_one_json_line_per_event() {
+    let Some(events) = watch(&[]) else {
+        return;
+    };
+    let names: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "added",
+            "activated",
+            "caption",
+            "desktop",
+            "desktop",
+            "geometry",
+            "removed"
+        ]
+    );
+    for event in &events {
+        assert!(event["time"].as_str().unwrap().ends_with('Z'), "{event}");
+        assert_eq!(event.as_object().unwrap().len(), 3, "{event}");
+        if event["window"].is_null() {
+            // The current desktop changed.
+            assert_eq!(event["desktop"], 2);
+        } else {
+            let window: WindowInfo = serde_json::from_value(event["window"].clone()).unwrap();
+            assert_eq!(window.id, "{00000006-aaaa-bbbb-cccc-dddddddddddd}");
+        }
+    }
+    assert_eq!(events[2]["window"]["caption"], "renamed");
+    assert_eq!(events[3]["window"]["desktops"], serde_json::json!([3]));
+    assert_eq!(events[5]["window"]["geometry"]["width"], 3);
+}
+
+#[test]
+fn watch_outputs_selected_events() {
+    let Some(events) = watch(&["--events", "caption,removed"]) else {
+        return;
+    };
+    let names: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["caption", "removed"]);
+}
+
+#[test]
+fn search_sync_waits_for_a_match() {
+    // A new window, and existing windows whose caption or class change.
+    for scenario in [
+        r#"setTimeout(() => mock.add({caption: "Target"}), 20);"#,
+        r#"setTimeout(() => { mock.windows[0].caption = "Target"; }, 20);"#,
+        r#"const w = mock.add({caption: "Other"});
+           setTimeout(() => { w.caption = "Target"; }, 20);"#,
+        r#"setTimeout(() => {
+               mock.windows[1].resourceClass = "Target";
+               mock.windows[1].windowClassChanged.emit();
+           }, 20);"#,
+    ] {
+        let Some(output) = run_with(
+            &[
+                "search",
+                "--sync",
+                "--timeout",
+                "1",
+                "Target",
+                "getwindowname",
+            ],
+            false,
+            "",
+            scenario,
+        ) else {
+            return;
+        };
+        assert_eq!(output.errors, Vec::<String>::new(), "{scenario}");
+        assert_eq!(output.results.len(), 1, "{scenario}");
+    }
+}
+
+#[test]
+fn search_sync_times_out() {
+    let Some(output) = run_with(
+        &["search", "--sync", "--timeout", "0.05", "Target"],
+        false,
+        "",
+        r#"mock.add({caption: "Other"});"#,
+    ) else {
+        return;
+    };
+    assert_eq!(output.errors, ["Timed out waiting for --sync"]);
+    assert_eq!(output.results, Vec::<String>::new());
+}
+
+/// Runs `search` with `args`, returning the numbers of the mock windows found.
+fn search_order(args: &[&str], setup: &str) -> Option<Vec<u32>> {
+    let args: Vec<&str> = ["search"].iter().chain(args).copied().collect();
+    let output = run_with(&args, false, setup, "")?;
+    assert_eq!(output.errors, Vec::<String>::new());
+    Some(
+        output
+            .results
+            .iter()
+            .map(|id| id[1..9].parse().unwrap())
+            .collect(),
+    )
+}
+
+#[test]
+fn search_sorts_windows() {
+    let all = ["--class", "."];
+    // Raise "Mozilla Firefox" (2) above "Konsole - build" (5).
+    let raise = "mock.raise(mock.windows[1]);";
+    let cases: &[(&[&str], &str, &[u32])] = &[
+        (&[], "", &[1, 2, 3, 4, 5]),
+        (&["--sort", "stacking"], raise, &[2, 5, 4, 3, 1]),
+        (
+            &["--sort", "stacking", "--reverse"],
+            raise,
+            &[1, 3, 4, 5, 2],
+        ),
+        (&["--sort", "stacking", "--limit", "2"], raise, &[2, 5]),
+        (&["--sort", "focus-history"], raise, &[1, 2, 5, 4, 3]),
+        (&["--sort", "title"], "", &[1, 5, 2, 4, 3]),
+        (&["--sort", "title", "--reverse"], "", &[3, 4, 2, 5, 1]),
+        // Equal keys keep the order of the window list.
+        (&["--sort", "class"], "", &[2, 3, 1, 5, 4]),
+        (&["--sort", "class", "--reverse"], "", &[4, 1, 5, 2, 3]),
+        (&["--sort", "area"], "", &[4, 3, 5, 1, 2]),
+        (&["--sort", "x", "--reverse"], "", &[5, 2, 3, 1, 4]),
+    ];
+    for (args, setup, expected) in cases {
+        let args: Vec<&str> = all.iter().chain(args.iter()).copied().collect();
+        let Some(order) = search_order(&args, setup) else {
+            return;
+        };
+        assert_eq!(&order, expected, "{args:?}");
+    }
+}
+
+#[test]
+fn stack_operations_keep_order_without_duplicates() {
+    // Each chain saves the windows of one class as `s`, then combines those of
+    // another class with it.
+    let cases: &[(&str, &[u32])] = &[
+        // Windows of the saved stack are appended, unless already there.
+        (
+            "firefox savewindowstack s search --class konsole|firefox stackunion s",
+            &[1, 2, 3, 5],
+        ),
+        (
+            "konsole savewindowstack s search --class firefox stackunion s",
+            &[2, 3, 1, 5],
+        ),
+        (
+            "firefox savewindowstack s search --class firefox stackunion s",
+            &[2, 3],
+        ),
+        // The order of the current stack is kept.
+        (
+            "konsole|plasma savewindowstack s search --class firefox|konsole stackintersect s",
+            &[1, 5],
+        ),
+        (
+            "firefox savewindowstack s search --class firefox stackintersect s",
+            &[2, 3],
+        ),
+        (
+            "plasma savewindowstack s search --class firefox stackintersect s",
+            &[],
+        ),
+        (
+            "firefox savewindowstack s search --class . stackdiff s",
+            &[1, 4, 5],
+        ),
+        (
+            "firefox savewindowstack s search --class firefox stackdiff s",
+            &[],
+        ),
+        (
+            "plasma savewindowstack s search --class konsole stackdiff s",
+            &[1, 5],
+        ),
+        // Combining a combined stack again doesn't duplicate windows either.
+        (
+            "firefox savewindowstack f search --class konsole stackunion f \
+             savewindowstack s stackunion s stackunion f",
+            &[1, 5, 2, 3],
+        ),
+    ];
+    for (chain, expected) in cases {
+        let args: Vec<&str> = ["--class"]
+            .into_iter()
+            .chain(chain.split_whitespace())
+            .collect();
+        let Some(order) = search_order(&args, "") else {
+            return;
+        };
+        assert_eq!(&order, expected, "{chain}");
+    }
+
+    let Some(output) = run(&["search", "--class", "firefox", "stackunion", "nope"]) else {
+        return;
+    };
+    assert_eq!(output.errors, ["Unknown window stack nope"]);
+}
+
+#[test]
+fn list_desktops_outputs_one_row_per_desktop() {
+    let setup = r#"
+        workspace.currentDesktop = workspace.desktops[2];
+        workspace.desktops[1].name = "Mail\tand chat";
+    "#;
+    let Some(output) = run_with(&["list_desktops"], false, setup, "") else {
+        return;
+    };
+    assert_eq!(output.errors, Vec::<String>::new());
+    assert_eq!(
+        output.results,
+        [
+            "1\t-\td0000001-0000-0000-0000-000000000001\tDesktop 1",
+            "2\t-\td0000002-0000-0000-0000-000000000002\tMail\tand chat",
+            "3\t*\td0000003-0000-0000-0000-000000000003\tDesktop 3",
+            "4\t-\td0000004-0000-0000-0000-000000000004\tDesktop 4",
+        ]
+    );
+
+    let output = run_with(&["list_desktops"], true, setup, "").unwrap();
+    let desktops: Vec<Value> = output
+        .results
+        .iter()
+        .map(|r| serde_json::from_str(r).unwrap())
+        .collect();
+    assert_eq!(desktops.len(), 4);
+    assert_eq!(
+        desktops[2],
+        serde_json::json!({
+            "id": "d0000003-0000-0000-0000-000000000003",
+            "number": 3,
+            "name": "Desktop 3",
+            "row": 2,
+            "current": true,
+        })
+    );
+    assert_eq!(desktops[1]["row"], 1);
+    assert_eq!(desktops[1]["current"], false);
+}